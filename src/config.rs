@@ -1,14 +1,22 @@
+pub mod file;
+
 use anyhow::{bail, Result};
 use envconfig::Envconfig;
 use std::{collections::HashMap, time::Duration};
 
+use self::file::{Bme680Config, ExternalConfig, FileConfig, GpioConfig, InitialState};
+
+const DEFAULT_BME680_PATH: &str = "/dev/i2c-1";
+
+/// Settings read from `JH_*` environment variables. Anything set here takes
+/// precedence over the configuration file.
 #[derive(Envconfig, Default)]
-pub struct Config {
-    #[envconfig(from = "JH_ADDR", default = "0.0.0.0:7200")]
-    pub endpoint: String,
+pub struct EnvConfig {
+    #[envconfig(from = "JH_ADDR")]
+    pub endpoint: Option<String>,
 
-    #[envconfig(from = "JH_NAME", default = "roomA")]
-    pub metrics_name: String,
+    #[envconfig(from = "JH_NAME")]
+    pub metrics_name: Option<String>,
 
     #[envconfig(from = "JH_GPIOS")]
     pub gpios: Option<String>,
@@ -16,11 +24,11 @@ pub struct Config {
     #[envconfig(from = "JH_EXTERNALS")]
     pub externals: Option<String>,
 
-    #[envconfig(from = "JH_BME680", default = "/dev/i2c-1")]
-    pub bme680: String,
+    #[envconfig(from = "JH_BME680")]
+    pub bme680: Option<String>,
 
-    #[envconfig(from = "JH_RESOLUTION_MS", default = "1000")]
-    pub resolution_ms: u64,
+    #[envconfig(from = "JH_RESOLUTION_MS")]
+    pub resolution_ms: Option<u64>,
 
     #[envconfig(from = "JH_API_CREDENTIALS")]
     pub api_credentials: Option<String>,
@@ -38,7 +46,139 @@ pub struct Config {
     pub webhook_url: Option<String>,
 }
 
+pub struct Config {
+    pub endpoint: String,
+    pub metrics_name: String,
+    pub gpios: Option<String>,
+    pub externals: Option<String>,
+    pub bme680: Option<String>,
+    pub resolution_ms: u64,
+    pub api_credentials: Option<String>,
+    pub location: Option<String>,
+    pub mqtt_connection: Option<String>,
+    pub heaterfan_mac: Option<String>,
+    pub webhook_url: Option<String>,
+    pub file: FileConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            endpoint: "0.0.0.0:7200".into(),
+            metrics_name: "roomA".into(),
+            gpios: None,
+            externals: None,
+            bme680: None,
+            resolution_ms: 1000,
+            api_credentials: None,
+            location: None,
+            mqtt_connection: None,
+            heaterfan_mac: None,
+            webhook_url: None,
+            file: FileConfig::default(),
+        }
+    }
+}
+
 impl Config {
+    /// Reads the configuration file at `path` (if any) and applies the `JH_*`
+    /// environment variables on top.
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let file = match path {
+            Some(p) => FileConfig::from_path(p)?,
+            None => FileConfig::default(),
+        };
+        let config = Config::merge(file, EnvConfig::init_from_env()?);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings that parse fine but can't work together.
+    fn validate(&self) -> Result<()> {
+        if self.file.switch_state_file.is_none() {
+            if let Some(gpio) = self
+                .file
+                .gpio
+                .iter()
+                .find(|g| g.initial == Some(InitialState::Restore))
+            {
+                bail!(
+                    "GPIO '{}' restores its state, but there's no switch_state_file",
+                    gpio.name
+                );
+            }
+        }
+        for sensor in &self.file.bme680 {
+            sensor.iaq.validate()?;
+        }
+        Ok(())
+    }
+
+    fn merge(mut file: FileConfig, env: EnvConfig) -> Self {
+        let defaults = Config::default();
+        let mqtt = file.mqtt_heater.clone().unwrap_or_default();
+        Config {
+            endpoint: env
+                .endpoint
+                .or_else(|| file.endpoint.take())
+                .unwrap_or(defaults.endpoint),
+            metrics_name: env
+                .metrics_name
+                .or_else(|| file.name.take())
+                .unwrap_or(defaults.metrics_name),
+            gpios: env.gpios,
+            externals: env.externals,
+            bme680: env.bme680,
+            resolution_ms: env
+                .resolution_ms
+                .or(file.resolution_ms)
+                .unwrap_or(defaults.resolution_ms),
+            api_credentials: env.api_credentials,
+            location: env.location.or_else(|| file.location.take()),
+            mqtt_connection: env.mqtt_connection.or(mqtt.connection),
+            heaterfan_mac: env.heaterfan_mac.or(mqtt.mac),
+            webhook_url: env.webhook_url.or(mqtt.webhook_url),
+            file,
+        }
+    }
+
+    /// GPIO switches from the config file, with `JH_GPIOS` entries replacing
+    /// those of the same name.
+    pub async fn gpio_switches(&self) -> Vec<GpioConfig> {
+        let mut switches = self.file.gpio.clone();
+        for (name, pin) in self.parsed_gpios().await {
+            switches.retain(|s| s.name != name);
+            switches.push(GpioConfig::with_pin(name, pin));
+        }
+        switches
+    }
+
+    /// External sensors from the config file, with `JH_EXTERNALS` entries replacing
+    /// those with the same path.
+    pub async fn external_sensors(&self) -> Vec<ExternalConfig> {
+        let mut externals = self.file.external.clone();
+        for path in self.parsed_externals().await {
+            externals.retain(|e| e.path != path);
+            externals.push(ExternalConfig::with_path(path));
+        }
+        externals
+    }
+
+    /// BME680 sensors from the config file, plus the primary address on the
+    /// `JH_BME680` bus. Falls back to `/dev/i2c-1` if neither is set.
+    pub fn bme680_sensors(&self) -> Vec<Bme680Config> {
+        let mut sensors = self.file.bme680.clone();
+        if let Some(path) = &self.bme680 {
+            let env_sensor = Bme680Config::with_path(path);
+            sensors.retain(|s| s.path != env_sensor.path || s.address != env_sensor.address);
+            sensors.push(env_sensor);
+        }
+        if sensors.is_empty() {
+            sensors.push(Bme680Config::with_path(DEFAULT_BME680_PATH));
+        }
+        sensors
+    }
+
     pub async fn parsed_gpios(&self) -> Vec<(String, u32)> {
         match &self.gpios {
             Some(tuples) => tuples
@@ -127,6 +267,7 @@ impl Config {
 #[cfg(test)]
 mod tests {
     #![allow(non_snake_case)]
+    use super::file::InstanceConfig;
     use super::*;
 
     #[async_std::test]
@@ -146,4 +287,172 @@ mod tests {
         };
         assert_eq!(conf.parsed_credentials().await.unwrap(), expected);
     }
+
+    #[test]
+    fn test_Config_validate_needs_state_file_to_restore() {
+        let raw = r#"
+            [[gpio]]
+            name = "relay"
+            pin = 17
+            initial = "restore-last"
+            "#;
+        let mut conf = Config {
+            file: FileConfig::parse(raw).unwrap(),
+            ..Config::default()
+        };
+        assert!(conf.validate().is_err());
+
+        conf.file.switch_state_file = Some("/var/lib/jotunheim/switches.toml".into());
+        assert!(conf.validate().is_ok());
+    }
+
+    #[test]
+    fn test_Config_validate_rejects_iaq_out_of_range() {
+        let raw = r#"
+            [[bme680]]
+            path = "/dev/i2c-1"
+            iaq = { humidity_baseline = 100.0 }
+            "#;
+        let mut conf = Config {
+            file: FileConfig::parse(raw).unwrap(),
+            ..Config::default()
+        };
+        assert!(conf.validate().is_err());
+
+        conf.file.bme680[0].iaq.humidity_baseline = 40.0;
+        conf.file.bme680[0].iaq.humidity_weighting = 1.5;
+        assert!(conf.validate().is_err());
+
+        conf.file.bme680[0].iaq.humidity_weighting = 1.0;
+        assert!(conf.validate().is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_Config_merge_env_overrides_file() {
+        let file = FileConfig::parse(
+            r#"
+            endpoint = "127.0.0.1:8080"
+            name = "attic"
+            resolution_ms = 5000
+
+            [[gpio]]
+            name = "relay"
+            pin = 17
+
+            [[gpio]]
+            name = "fan"
+            pin = 22
+
+            [mqtt_heater]
+            connection = "mqtt://10.1.0.38"
+            mac = "3c39e723d3e2"
+            "#,
+        )
+        .unwrap();
+        let env = EnvConfig {
+            metrics_name: Some("roomB".into()),
+            gpios: Some("relay:27".into()),
+            heaterfan_mac: Some("aabbccddeeff".into()),
+            ..Default::default()
+        };
+        let conf = Config::merge(file, env);
+
+        assert_eq!(conf.endpoint, "127.0.0.1:8080");
+        assert_eq!(conf.metrics_name, "roomB");
+        assert_eq!(conf.resolution(), Duration::from_millis(5000));
+        assert_eq!(conf.mqtt_connection.as_deref(), Some("mqtt://10.1.0.38"));
+        assert_eq!(conf.heaterfan_mac().unwrap(), "aabbccddeeff");
+        assert_eq!(
+            conf.gpio_switches().await,
+            vec![
+                GpioConfig::with_pin("fan", 22),
+                GpioConfig::with_pin("relay", 27)
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_Config_bme680_sensors_has_default() {
+        let conf = Config::default();
+        assert_eq!(
+            conf.bme680_sensors(),
+            vec![Bme680Config::with_path("/dev/i2c-1")]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_Config_bme680_sensors_on_both_addresses() {
+        let file = FileConfig::parse(
+            r#"
+            [[bme680]]
+            path = "/dev/i2c-1"
+
+            [[bme680]]
+            path = "/dev/i2c-1"
+            address = "secondary"
+            name = "outside"
+            "#,
+        )
+        .unwrap();
+        let env = EnvConfig {
+            bme680: Some("/dev/i2c-0".into()),
+            ..Default::default()
+        };
+        let sensors = Config::merge(file, env).bme680_sensors();
+
+        assert_eq!(sensors.len(), 3);
+        assert_eq!(sensors[1].address, file::Bme680Address::Secondary);
+        assert_eq!(sensors[1].name.as_deref(), Some("outside"));
+        assert_eq!(sensors[2], Bme680Config::with_path("/dev/i2c-0"));
+    }
+
+    #[async_std::test]
+    async fn test_Config_external_instances_fall_back_to_globals() {
+        let file = FileConfig::parse(
+            r#"
+            name = "roomA"
+            resolution_ms = 1000
+
+            [[external]]
+            path = "/opt/co2.py"
+            name = "co2"
+            resolution_ms = 30000
+            args = ["--port", "/dev/ttyUSB0"]
+            labels = { room = "A", floor = "1" }
+
+            [[external]]
+            path = "/opt/lux.sh"
+            "#,
+        )
+        .unwrap();
+        let conf = Config::merge(file, EnvConfig::default());
+        let externals = conf.external_sensors().await;
+
+        assert_eq!(externals[0].name_or(&conf.metrics_name), "co2");
+        assert_eq!(
+            externals[0].resolution_or(conf.resolution()),
+            Duration::from_millis(30000)
+        );
+        assert_eq!(externals[0].args, vec!["--port", "/dev/ttyUSB0"]);
+        assert_eq!(
+            externals[0].labels.keys().collect::<Vec<_>>(),
+            vec!["floor", "room"]
+        );
+        assert_eq!(externals[1].name_or(&conf.metrics_name), "roomA");
+        assert_eq!(
+            externals[1].resolution_or(conf.resolution()),
+            conf.resolution()
+        );
+    }
+
+    #[test]
+    fn test_FileConfig_example_parses() {
+        let file = FileConfig::parse(include_str!("../config.example.toml")).unwrap();
+        let window = &file.bme680[1].settings;
+
+        assert_eq!(file.bme680[0].settings, file::Bme680Settings::default());
+        assert!(!window.gas.enabled);
+        assert_eq!(window.temperature_offset, -0.8);
+        assert_eq!(window.humidity_oversampling, file::Oversampling::X2);
+    }
 }
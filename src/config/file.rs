@@ -0,0 +1,554 @@
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+/// Static labels added to every reading of a sensor, e.g. `{ room = "A", floor = "1" }`.
+pub type Labels = BTreeMap<String, String>;
+
+/// Contents of the (optional) TOML configuration file passed via `--config`.
+///
+/// Every key is optional, anything that's not set falls back to the `JH_*`
+/// environment variables or their defaults.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub endpoint: Option<String>,
+    pub name: Option<String>,
+    pub resolution_ms: Option<u64>,
+    pub location: Option<String>,
+    /// Prefix for all metric names, e.g. `jotunheim`
+    pub namespace: Option<String>,
+    /// Readings are dropped after this many missed updates, 0 keeps them forever. Defaults to 3.
+    pub stale_after_intervals: Option<u32>,
+    /// Where switches remember their last commanded state, nothing is kept if unset
+    pub switch_state_file: Option<String>,
+    pub gpio: Vec<GpioConfig>,
+    pub external: Vec<ExternalConfig>,
+    /// How `external_duration_seconds` is reported
+    pub external_duration: DurationConfig,
+    pub scrape: Vec<ScrapeConfig>,
+    /// How `scrape_duration_seconds` is reported
+    pub scrape_duration: DurationConfig,
+    pub bme680: Vec<Bme680Config>,
+    pub netatmo: Option<NetatmoConfig>,
+    pub mqtt_heater: Option<MqttHeaterConfig>,
+    pub derived: DerivedConfig,
+    pub history: HistoryConfig,
+    pub remote_write: Option<RemoteWriteConfig>,
+    pub pushgateway: Option<PushgatewayConfig>,
+    pub influx: Option<InfluxConfig>,
+    pub mqtt: Option<MqttSinkConfig>,
+}
+
+impl FileConfig {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)?;
+        Self::parse(&raw)
+    }
+
+    pub fn parse(raw: &str) -> Result<Self> {
+        toml::from_str(raw).map_err(From::from)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GpioConfig {
+    pub name: String,
+    pub pin: u32,
+    /// Switch to this state at start, the pin is left alone if unset
+    #[serde(default)]
+    pub initial: Option<InitialState>,
+    /// Without an initial state, take the state from the pin's level at start
+    /// instead of assuming off
+    #[serde(default)]
+    pub read_back: bool,
+    /// Set to `false` for relays that switch on when the pin is low, states
+    /// are always reported as on/off rather than the pin's level
+    #[serde(default = "default_active_high")]
+    pub active_high: bool,
+}
+
+fn default_active_high() -> bool {
+    true
+}
+
+impl GpioConfig {
+    pub fn with_pin<S: Into<String>>(name: S, pin: u32) -> Self {
+        GpioConfig {
+            name: name.into(),
+            pin,
+            initial: None,
+            read_back: false,
+            active_high: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InitialState {
+    #[serde(alias = "always-on")]
+    On,
+    #[serde(alias = "always-off")]
+    Off,
+    /// The last commanded state from `switch_state_file`, as if unset if there is none
+    #[serde(alias = "restore-last")]
+    Restore,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ExternalConfig {
+    pub path: String,
+    pub name: Option<String>,
+    pub resolution_ms: Option<u64>,
+    #[serde(default)]
+    pub labels: Labels,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Kill the command if it runs longer than this, defaults to the resolution
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub mode: ExternalMode,
+    /// Variables set for the command, its environment is empty otherwise
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Names of variables passed through from jotunheim's own environment, e.g. `PATH`
+    #[serde(default)]
+    pub env_passthrough: Vec<String>,
+    /// Working directory of the command
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Kind of the reading in `plain` format
+    pub kind: Option<String>,
+    /// Unit of the reading in `plain` format
+    pub unit: Option<String>,
+}
+
+/// `poll` runs the command every resolution tick, `stream` starts it once and
+/// parses each line of its stdout on its own. `prometheus-text` is streamed
+/// in blocks ending with a blank line, so `# TYPE` lines apply.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExternalMode {
+    #[default]
+    Poll,
+    Stream,
+}
+
+/// What a command writes to stdout: `json` readings, the Prometheus text
+/// exposition format, InfluxDB line protocol or a single `plain` number.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    PrometheusText,
+    InfluxLine,
+    Plain,
+}
+
+impl ExternalConfig {
+    pub fn with_path<S: Into<String>>(path: S) -> Self {
+        ExternalConfig {
+            path: path.into(),
+            name: None,
+            resolution_ms: None,
+            labels: Labels::new(),
+            args: vec![],
+            timeout_ms: None,
+            mode: ExternalMode::default(),
+            env: BTreeMap::new(),
+            env_passthrough: vec![],
+            cwd: None,
+            format: OutputFormat::default(),
+            kind: None,
+            unit: None,
+        }
+    }
+}
+
+/// A remote Prometheus endpoint whose series are republished as `<name>_<metric>`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ScrapeConfig {
+    pub url: String,
+    pub name: Option<String>,
+    pub resolution_ms: Option<u64>,
+    /// Give up on a scrape after this long, defaults to the resolution
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub labels: Labels,
+    /// Metric names to keep, `*` matches anything. Keeps everything if empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Metric names to drop, applied after `include`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Renames series labels, e.g. `{ instance = "device" }`
+    #[serde(default)]
+    pub rename_labels: BTreeMap<String, String>,
+    /// Series labels to remove
+    #[serde(default)]
+    pub drop_labels: Vec<String>,
+}
+
+/// Buckets of a duration histogram, or quantiles to report it as a summary instead.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DurationConfig {
+    /// Upper bounds in seconds, Prometheus' defaults if empty
+    pub buckets: Vec<f64>,
+    pub quantiles: Vec<f64>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Bme680Config {
+    pub path: String,
+    #[serde(default)]
+    pub address: Bme680Address,
+    pub name: Option<String>,
+    pub resolution_ms: Option<u64>,
+    #[serde(default)]
+    pub labels: Labels,
+    #[serde(default)]
+    pub settings: Bme680Settings,
+    #[serde(default)]
+    pub iaq: IaqConfig,
+}
+
+impl Bme680Config {
+    pub fn with_path<S: Into<String>>(path: S) -> Self {
+        Bme680Config {
+            path: path.into(),
+            address: Bme680Address::default(),
+            name: None,
+            resolution_ms: None,
+            labels: Labels::new(),
+            settings: Bme680Settings::default(),
+            iaq: IaqConfig::default(),
+        }
+    }
+}
+
+/// Measurement settings written to a BME680 once at start.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bme680Settings {
+    pub humidity_oversampling: Oversampling,
+    pub pressure_oversampling: Oversampling,
+    pub temperature_oversampling: Oversampling,
+    /// IIR filter coefficient: 0, 1, 3, 7, 15, 31, 63 or 127
+    pub filter_size: u8,
+    pub temperature_offset: f32,
+    pub gas: GasHeaterConfig,
+}
+
+impl Default for Bme680Settings {
+    fn default() -> Self {
+        Bme680Settings {
+            humidity_oversampling: Oversampling::X2,
+            pressure_oversampling: Oversampling::X4,
+            temperature_oversampling: Oversampling::X8,
+            filter_size: 3,
+            temperature_offset: -2.2,
+            gas: GasHeaterConfig::default(),
+        }
+    }
+}
+
+/// Indoor air quality index calculation from the gas resistance, requires the gas heater.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IaqConfig {
+    pub enabled: bool,
+    /// Number of readings to collect before the first baseline is set
+    pub burn_in_samples: usize,
+    /// Relative humidity (%) considered ideal
+    pub humidity_baseline: f64,
+    /// Share of humidity in the score, the remainder is gas resistance
+    pub humidity_weighting: f64,
+    /// File to keep the gas resistance baseline in across restarts
+    pub baseline_path: Option<String>,
+}
+
+impl IaqConfig {
+    /// Scores are computed relative to the baseline and weighting, values
+    /// outside their range would divide by zero or go negative.
+    pub fn validate(&self) -> Result<()> {
+        if !(self.humidity_baseline > 0.0 && self.humidity_baseline < 100.0) {
+            bail!(
+                "IAQ humidity_baseline must be between 0 and 100, not {}",
+                self.humidity_baseline
+            );
+        }
+        if !(0.0..=1.0).contains(&self.humidity_weighting) {
+            bail!(
+                "IAQ humidity_weighting must be between 0 and 1, not {}",
+                self.humidity_weighting
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for IaqConfig {
+    fn default() -> Self {
+        IaqConfig {
+            enabled: true,
+            burn_in_samples: 300,
+            humidity_baseline: 40.0,
+            humidity_weighting: 0.25,
+            baseline_path: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Oversampling {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "1x")]
+    X1,
+    #[serde(rename = "2x")]
+    X2,
+    #[serde(rename = "4x")]
+    X4,
+    #[serde(rename = "8x")]
+    X8,
+    #[serde(rename = "16x")]
+    X16,
+}
+
+/// The gas heater profile, disabling it skips the gas resistance reading.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GasHeaterConfig {
+    pub enabled: bool,
+    pub temperature_celsius: u16,
+    pub duration_ms: u64,
+    pub ambient_celsius: i8,
+}
+
+impl Default for GasHeaterConfig {
+    fn default() -> Self {
+        GasHeaterConfig {
+            enabled: true,
+            temperature_celsius: 320,
+            duration_ms: 1500,
+            ambient_celsius: 25,
+        }
+    }
+}
+
+/// I2C address of a BME680: primary is 0x76, secondary 0x77.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Bme680Address {
+    #[default]
+    Primary,
+    Secondary,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetatmoConfig {
+    pub device_id: String,
+    pub username: String,
+    pub password: String,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// Dew point, absolute humidity, heat index and sea-level pressure computed
+/// from the temperature, humidity and pressure readings of each sensor.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DerivedConfig {
+    pub enabled: bool,
+    /// Altitude of the node in meters, required for sea-level pressure
+    pub altitude_m: Option<f64>,
+}
+
+/// Keeps recent readings in memory and serves them on `/history`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    /// Readings older than this are dropped
+    pub retention_s: u64,
+    /// Upper bound of readings kept per series
+    pub max_points: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: false,
+            retention_s: 24 * 60 * 60,
+            max_points: 10_000,
+        }
+    }
+}
+
+/// Pushes all metrics to a Prometheus remote-write endpoint.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteWriteConfig {
+    pub url: String,
+    /// How often metrics are pushed, defaults to the global resolution
+    pub interval_ms: Option<u64>,
+    /// Defaults to the interval
+    pub timeout_ms: Option<u64>,
+    /// Pushes kept while the endpoint is unreachable, the oldest are dropped first
+    #[serde(default = "default_max_pending")]
+    pub max_pending: usize,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Extra HTTP headers, e.g. `{ X-Scope-OrgID = "home" }`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_max_pending() -> usize {
+    100
+}
+
+/// Pushes the `/metrics` output to a Prometheus Pushgateway.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PushgatewayConfig {
+    pub url: String,
+    #[serde(default = "default_job")]
+    pub job: String,
+    /// Defaults to the global name
+    pub instance: Option<String>,
+    /// More labels for the grouping key
+    #[serde(default)]
+    pub grouping: Labels,
+    /// Defaults to the global resolution
+    pub interval_ms: Option<u64>,
+    /// Defaults to the interval
+    pub timeout_ms: Option<u64>,
+}
+
+fn default_job() -> String {
+    "jotunheim".into()
+}
+
+/// Writes every reading as line protocol to InfluxDB v2.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxConfig {
+    /// Base URL of the server, e.g. `http://localhost:8086`
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: Option<String>,
+    /// Readings are written in batches this often, defaults to the global resolution
+    pub flush_interval_ms: Option<u64>,
+    /// Defaults to the flush interval
+    pub timeout_ms: Option<u64>,
+    /// Lines kept while the server is unreachable, the oldest are dropped first
+    #[serde(default = "default_max_pending_lines")]
+    pub max_pending_lines: usize,
+}
+
+fn default_max_pending_lines() -> usize {
+    10_000
+}
+
+/// Publishes every reading to `<prefix>/<name>/<kind>/...`, switches listen on
+/// `<prefix>/<node>/switch/<name>/set`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSinkConfig {
+    /// Broker URL like `mqtt://localhost:1883`, defaults to `JH_MQTT_CONN`
+    pub connection: Option<String>,
+    pub prefix: String,
+    /// 0, 1 or 2
+    pub qos: u8,
+    pub retain: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Announce gauges via Home Assistant's MQTT discovery, and switches with `switch-mqtt`
+    pub homeassistant: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttSinkConfig {
+    fn default() -> Self {
+        MqttSinkConfig {
+            connection: None,
+            prefix: "jotunheim".into(),
+            qos: 1,
+            retain: true,
+            username: None,
+            password: None,
+            homeassistant: false,
+            discovery_prefix: "homeassistant".into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttHeaterConfig {
+    pub connection: Option<String>,
+    pub mac: Option<String>,
+    pub webhook_url: Option<String>,
+    pub name: Option<String>,
+    pub labels: Labels,
+}
+
+/// Per-instance overrides for the metric name and polling interval, anything
+/// unset falls back to the global `name` and `resolution_ms`.
+pub trait InstanceConfig {
+    fn instance_name(&self) -> Option<&String>;
+    fn instance_resolution_ms(&self) -> Option<u64>;
+
+    fn name_or(&self, default: &str) -> String {
+        self.instance_name()
+            .cloned()
+            .unwrap_or_else(|| default.to_string())
+    }
+
+    fn resolution_or(&self, default: Duration) -> Duration {
+        self.instance_resolution_ms()
+            .map(Duration::from_millis)
+            .unwrap_or(default)
+    }
+}
+
+impl InstanceConfig for ExternalConfig {
+    fn instance_name(&self) -> Option<&String> {
+        self.name.as_ref()
+    }
+
+    fn instance_resolution_ms(&self) -> Option<u64> {
+        self.resolution_ms
+    }
+}
+
+impl InstanceConfig for ScrapeConfig {
+    fn instance_name(&self) -> Option<&String> {
+        self.name.as_ref()
+    }
+
+    fn instance_resolution_ms(&self) -> Option<u64> {
+        self.resolution_ms
+    }
+}
+
+impl InstanceConfig for Bme680Config {
+    fn instance_name(&self) -> Option<&String> {
+        self.name.as_ref()
+    }
+
+    fn instance_resolution_ms(&self) -> Option<u64> {
+        self.resolution_ms
+    }
+}
@@ -1,14 +1,25 @@
-use std::collections::HashMap;
+mod summary;
 
-use crate::msg::{EncodeData, SensorReading, SetupMetrics};
-use log::{error, info};
-use prometheus::{CounterVec, Encoder, GaugeVec, Opts, Registry, TextEncoder};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::msg::{EncodeData, Gather, MetricSpec, SensorReading, SetupMetrics};
+use anyhow::{anyhow, bail};
+use log::{debug, error, info};
+use prometheus::{
+    CounterVec, Encoder, GaugeVec, HistogramOpts, HistogramVec, Opts, Registry, TextEncoder,
+};
+use summary::SummaryVec;
 use uuid::Uuid;
 use xactor::*;
 
 enum DataCollector {
     Gauge(GaugeVec),
     Counter(CounterVec),
+    Histogram(HistogramVec),
+    Summary(SummaryVec),
 }
 
 impl DataCollector {
@@ -28,6 +39,25 @@ impl DataCollector {
             DataCollector::Counter(c) => {
                 c.with_label_values(label_vals).inc();
             }
+            _ => {}
+        }
+    }
+    pub fn inc_by(&self, label_vals: &[&str], value: f64) {
+        match self {
+            DataCollector::Gauge(c) => {
+                c.with_label_values(label_vals).add(value);
+            }
+            DataCollector::Counter(c) => {
+                c.with_label_values(label_vals).inc_by(value);
+            }
+            _ => {}
+        }
+    }
+    pub fn observe(&self, label_vals: &[&str], value: f64) {
+        match self {
+            DataCollector::Histogram(c) => c.with_label_values(label_vals).observe(value),
+            DataCollector::Summary(c) => c.observe(label_vals, value),
+            _ => {}
         }
     }
     pub fn dec(&self, label_vals: &[&str]) {
@@ -38,21 +68,210 @@ impl DataCollector {
             _ => {}
         }
     }
+    pub fn remove(&self, label_vals: &[&str]) {
+        let _ = match self {
+            DataCollector::Gauge(c) => c.remove_label_values(label_vals),
+            DataCollector::Counter(c) => c.remove_label_values(label_vals),
+            DataCollector::Histogram(c) => c.remove_label_values(label_vals),
+            DataCollector::Summary(c) => {
+                c.remove_label_values(label_vals);
+                Ok(())
+            }
+        };
+    }
+}
+
+/// Removes stale label sets even when nobody scrapes.
+#[message]
+#[derive(Clone, Debug)]
+struct Expire;
+
+struct Registered {
+    collector: DataCollector,
+    name: String,
+    ttl: Option<Duration>,
+    updated: HashMap<Vec<String>, Instant>,
 }
 
 pub(crate) struct PrometheusCollector {
     registry: Registry,
-    metrics: HashMap<Uuid, DataCollector>,
+    metrics: HashMap<Uuid, Registered>,
+    namespace: Option<String>,
+    stale_after: u32,
+    last_update: GaugeVec,
 }
 
+/// Replaces everything but `[a-zA-Z0-9_]` with `_` and makes sure the name
+/// doesn't start with a digit, e.g. `switch:relay` becomes `switch_relay`.
+pub fn sanitize_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if sanitized.is_empty() || sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// Builds the options for a metric, sanitizing all names.
+fn options(spec: &MetricSpec, default_namespace: Option<&String>) -> (Opts, Vec<String>) {
+    let mut opts = Opts::new(sanitize_name(&spec.name_with_unit()), spec.help.clone());
+    if let Some(namespace) = spec.namespace.as_ref().or(default_namespace) {
+        opts = opts.namespace(sanitize_name(namespace));
+    }
+    if let Some(subsystem) = &spec.subsystem {
+        opts = opts.subsystem(sanitize_name(subsystem));
+    }
+    let labels = spec
+        .labels
+        .iter()
+        .map(|l| sanitize_name(l).trim_start_matches("__").to_string())
+        .collect();
+    (opts, labels)
+}
+
+/// Namespace of the last update timestamps if none is configured.
+const LAST_UPDATE_NAMESPACE: &str = "jotunheim";
+
 impl PrometheusCollector {
-    pub fn new() -> Result<Self> {
+    /// `namespace` is prepended to every metric that doesn't set its own.
+    /// Label sets of gauges with an interval are removed after missing
+    /// `stale_after` updates, 0 keeps them forever. Counters never expire.
+    pub fn new(namespace: Option<String>, stale_after: u32) -> Result<Self> {
         let registry = Registry::new();
+        let opts = Opts::new(
+            "last_update_timestamp_seconds",
+            "When a collector last received a reading, in seconds since the epoch",
+        )
+        .subsystem("sensor")
+        // there's no collector name to go by, it needs a namespace of its own
+        .namespace(sanitize_name(
+            namespace.as_deref().unwrap_or(LAST_UPDATE_NAMESPACE),
+        ));
+        let last_update = GaugeVec::new(opts, &["collector"])?;
+        registry.register(Box::new(last_update.clone()))?;
         Ok(PrometheusCollector {
             registry,
             metrics: HashMap::new(),
+            namespace,
+            stale_after,
+            last_update,
         })
     }
+
+    fn register(&mut self, msg: SetupMetrics) -> Result<()> {
+        let (spec, collector) = match msg {
+            SetupMetrics::Gauge(spec) => {
+                let (opts, labels) = options(&spec, self.namespace.as_ref());
+                let labels: Vec<&str> = labels.iter().map(|s| &**s).collect();
+                (spec, DataCollector::Gauge(GaugeVec::new(opts, &labels)?))
+            }
+            SetupMetrics::Counter(spec) => {
+                let (opts, labels) = options(&spec, self.namespace.as_ref());
+                let labels: Vec<&str> = labels.iter().map(|s| &**s).collect();
+                (
+                    spec,
+                    DataCollector::Counter(CounterVec::new(opts, &labels)?),
+                )
+            }
+            SetupMetrics::Histogram(spec, buckets) => {
+                let (opts, labels) = options(&spec, self.namespace.as_ref());
+                let labels: Vec<&str> = labels.iter().map(|s| &**s).collect();
+                let mut opts = HistogramOpts::from(opts);
+                if !buckets.is_empty() {
+                    opts = opts.buckets(buckets);
+                }
+                let histogram = HistogramVec::new(opts, &labels)?;
+                (spec, DataCollector::Histogram(histogram))
+            }
+            SetupMetrics::Summary(spec, quantiles) => {
+                let (opts, labels) = options(&spec, self.namespace.as_ref());
+                let labels: Vec<&str> = labels.iter().map(|s| &**s).collect();
+                let summary = SummaryVec::new(opts, &labels, quantiles)?;
+                (spec, DataCollector::Summary(summary))
+            }
+        };
+        if self.metrics.contains_key(&spec.id) {
+            bail!("Collector '{}' ({}) is already set up", spec.name, spec.id);
+        }
+        match &collector {
+            DataCollector::Gauge(c) => self.registry.register(Box::new(c.clone())),
+            DataCollector::Counter(c) => self.registry.register(Box::new(c.clone())),
+            DataCollector::Histogram(c) => self.registry.register(Box::new(c.clone())),
+            DataCollector::Summary(c) => self.registry.register(Box::new(c.clone())),
+        }
+        .map_err(|e| anyhow!("Couldn't register '{}': {}", spec.name, e))?;
+
+        // a counter that disappears would look like a reset
+        let ttl = match (&collector, self.stale_after) {
+            (DataCollector::Gauge(_), n) if n > 0 => spec.interval.map(|i| i * n),
+            _ => None,
+        };
+        let name = options(&spec, self.namespace.as_ref()).0.fq_name();
+        self.metrics.insert(
+            spec.id,
+            Registered {
+                collector,
+                name,
+                ttl,
+                updated: HashMap::new(),
+            },
+        );
+        Ok(())
+    }
+
+    fn record(&mut self, msg: SensorReading) {
+        let registered = match self.metrics.get_mut(&msg.id) {
+            Some(r) => r,
+            None => {
+                error!("Couldn't find collector '{}'", msg.id);
+                return;
+            }
+        };
+        let dc = &registered.collector;
+        let lv: Vec<&str> = msg.labels.iter().map(|s| &**s).collect();
+        match msg.reading {
+            crate::msg::Value::Simple(v) => dc.set(&lv, v.into()),
+            crate::msg::Value::Inc => dc.inc(&lv),
+            crate::msg::Value::IncBy(v) => dc.inc_by(&lv, v.into()),
+            crate::msg::Value::Dec => dc.dec(&lv),
+            crate::msg::Value::Observe(v) => dc.observe(&lv, v),
+        }
+        if registered.ttl.is_some() {
+            registered.updated.insert(msg.labels, Instant::now());
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_update
+            .with_label_values(&[&registered.name])
+            .set(now.as_secs_f64());
+    }
+
+    /// Removes the label sets that weren't updated within their collector's TTL.
+    fn expire_stale(&mut self) {
+        let now = Instant::now();
+        for registered in self.metrics.values_mut() {
+            let Registered {
+                collector,
+                name,
+                ttl,
+                updated,
+            } = registered;
+            if let Some(ttl) = ttl {
+                updated.retain(|labels, at| {
+                    let fresh = now.duration_since(*at) < *ttl;
+                    if !fresh {
+                        debug!("Removing stale {:?} from '{}'", labels, name);
+                        let lv: Vec<&str> = labels.iter().map(|s| &**s).collect();
+                        collector.remove(&lv);
+                    }
+                    fresh
+                });
+            }
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -60,36 +279,26 @@ impl Actor for PrometheusCollector {
     async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
         ctx.subscribe::<SetupMetrics>().await?;
         ctx.subscribe::<SensorReading>().await?;
+        if self.stale_after > 0 {
+            ctx.send_interval(Expire, Duration::from_secs(60));
+        }
         Ok(())
     }
 }
 
+#[async_trait::async_trait]
+impl Handler<Expire> for PrometheusCollector {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Expire) {
+        self.expire_stale();
+    }
+}
+
 #[async_trait::async_trait]
 impl Handler<SetupMetrics> for PrometheusCollector {
     async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SetupMetrics) {
         info!("Setting up: {:?}", msg);
-        match msg {
-            SetupMetrics::Gauge(id, name, labels) => {
-                let options = Opts::new(name, "help");
-                let label_names: Vec<&str> = labels.iter().map(|s| &**s).collect();
-                let gauge = GaugeVec::new(options, &label_names).expect("Couldn't create Gauge");
-                self.registry
-                    .register(Box::new(gauge.clone()))
-                    .expect("Couldn't register metric to prometheus");
-                self.metrics
-                    .insert(id.clone(), DataCollector::Gauge(gauge.clone()));
-            }
-            SetupMetrics::Counter(id, name, labels) => {
-                let options = Opts::new(name, "help");
-                let label_names: Vec<&str> = labels.iter().map(|s| &**s).collect();
-                let gauge =
-                    CounterVec::new(options, &label_names).expect("Couldn't create Counter");
-                self.registry
-                    .register(Box::new(gauge.clone()))
-                    .expect("Couldn't register metric to prometheus");
-                self.metrics
-                    .insert(id.clone(), DataCollector::Counter(gauge.clone()));
-            }
+        if let Err(e) = self.register(msg) {
+            error!("{}", e);
         }
     }
 }
@@ -97,22 +306,14 @@ impl Handler<SetupMetrics> for PrometheusCollector {
 #[async_trait::async_trait]
 impl Handler<SensorReading> for PrometheusCollector {
     async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SensorReading) {
-        if let Some(dc) = self.metrics.get(&msg.id) {
-            let lv: Vec<&str> = msg.labels.iter().map(|s| &**s).collect();
-            match msg.reading {
-                crate::msg::Value::Simple(v) => dc.set(&lv, v.into()),
-                crate::msg::Value::Inc => dc.inc(&lv),
-                crate::msg::Value::Dec => dc.dec(&lv),
-            }
-        } else {
-            error!("Couldn't find collector '{}'", msg.id);
-        }
+        self.record(msg);
     }
 }
 
 #[async_trait::async_trait]
 impl Handler<EncodeData> for PrometheusCollector {
     async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: EncodeData) -> Result<String> {
+        self.expire_stale();
         let metrics = self.registry.gather();
         let mut buffer = vec![];
         let encoder = TextEncoder::new();
@@ -121,6 +322,147 @@ impl Handler<EncodeData> for PrometheusCollector {
     }
 }
 
+#[async_trait::async_trait]
+impl Handler<Gather> for PrometheusCollector {
+    async fn handle(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        _msg: Gather,
+    ) -> Vec<prometheus::proto::MetricFamily> {
+        self.expire_stale();
+        self.registry.gather()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use crate::msg::Value;
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("switch:relay"), "switch_relay");
+        assert_eq!(sanitize_name("room-A temp"), "room_A_temp");
+        assert_eq!(sanitize_name("1wire"), "_1wire");
+        assert_eq!(sanitize_name("roomA_errors_total"), "roomA_errors_total");
+    }
+
+    #[test]
+    fn test_PrometheusCollector_appends_units() {
+        let mut collector = PrometheusCollector::new(None, 0).unwrap();
+        for (name, unit) in [
+            ("energy_total", "joules"),
+            ("duration_seconds", "seconds"),
+            ("outdoor", "celsius"),
+        ] {
+            let spec = MetricSpec::new(Uuid::new_v4(), name, vec![]).unit(unit);
+            collector.register(SetupMetrics::Counter(spec)).unwrap();
+        }
+
+        let mut names: Vec<_> = collector.metrics.values().map(|m| m.name.clone()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["duration_seconds", "energy_joules_total", "outdoor_celsius"]
+        );
+    }
+
+    #[test]
+    fn test_PrometheusCollector_rejects_duplicates() {
+        let mut collector = PrometheusCollector::new(Some("jotunheim".into()), 0).unwrap();
+        let spec = MetricSpec::new(Uuid::new_v4(), "roomA", vec!["kind".into()])
+            .help("Readings of room A")
+            .subsystem("bme680");
+        collector.register(SetupMetrics::Gauge(spec)).unwrap();
+        let other = MetricSpec::new(Uuid::new_v4(), "roomA", vec!["kind".into()]);
+        assert!(collector
+            .register(SetupMetrics::Gauge(other.subsystem("bme680")))
+            .is_err());
+
+        let names: Vec<_> = collector
+            .registry
+            .gather()
+            .iter()
+            .map(|m| m.get_name().to_string())
+            .collect();
+        assert_eq!(names, vec!["jotunheim_bme680_roomA"]);
+    }
+
+    #[test]
+    fn test_DataCollector_observe_fills_buckets() {
+        let mut collector = PrometheusCollector::new(None, 0).unwrap();
+        let id = Uuid::new_v4();
+        let spec = MetricSpec::new(id, "duration_seconds", vec!["path".into()]);
+        collector
+            .register(SetupMetrics::Histogram(spec, vec![0.1, 1.0]))
+            .unwrap();
+        for v in [0.05, 0.5, 5.0] {
+            collector.metrics[&id].collector.observe(&["/bin/true"], v);
+        }
+
+        let families = collector.registry.gather();
+        let histogram = families[0].get_metric()[0].get_histogram();
+        assert_eq!(histogram.get_sample_count(), 3);
+        let counts: Vec<_> = histogram
+            .get_bucket()
+            .iter()
+            .map(|b| b.get_cumulative_count())
+            .collect();
+        assert_eq!(counts, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_PrometheusCollector_expires_stale_label_sets() {
+        let mut collector = PrometheusCollector::new(None, 3).unwrap();
+        let (stale, fresh, counter) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let labels = vec!["kind".to_string()];
+        collector
+            .register(SetupMetrics::Gauge(
+                MetricSpec::new(stale, "stale", labels.clone()).interval(Duration::ZERO),
+            ))
+            .unwrap();
+        collector
+            .register(SetupMetrics::Gauge(
+                MetricSpec::new(fresh, "fresh", labels.clone()).interval(Duration::from_secs(60)),
+            ))
+            .unwrap();
+        collector
+            .register(SetupMetrics::Counter(
+                MetricSpec::new(counter, "counter", labels.clone()).interval(Duration::ZERO),
+            ))
+            .unwrap();
+        for (id, reading) in [
+            (stale, Value::Simple(1.0)),
+            (fresh, Value::Simple(1.0)),
+            (counter, Value::Inc),
+        ] {
+            collector.record(SensorReading {
+                id,
+                reading,
+                labels: vec!["temperature".into()],
+                timestamp: None,
+            });
+        }
+        collector.expire_stale();
+
+        let names: Vec<_> = collector
+            .registry
+            .gather()
+            .iter()
+            .map(|m| m.get_name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "counter",
+                "fresh",
+                "jotunheim_sensor_last_update_timestamp_seconds"
+            ]
+        );
+    }
+}
+
 // lazy_static! {
 //     static ref A_INT_COUNTER: IntCounter =
 //         register_int_counter!("A_int_counter", "foobar").unwrap();
@@ -0,0 +1,177 @@
+use prometheus::{
+    core::{Collector, Desc},
+    proto, Opts,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Quantiles if none are configured, like the Go client's examples.
+pub const DEFAULT_QUANTILES: &[f64] = &[0.5, 0.9, 0.99];
+
+/// Observations older than this don't count towards the quantiles.
+const MAX_AGE: Duration = Duration::from_secs(600);
+
+#[derive(Default)]
+struct Series {
+    observations: VecDeque<(Instant, f64)>,
+    sum: f64,
+    count: u64,
+}
+
+impl Series {
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, _)) = self.observations.front() {
+            if now.duration_since(*at) < MAX_AGE {
+                break;
+            }
+            self.observations.pop_front();
+        }
+    }
+}
+
+/// A summary with a label vector, the prometheus crate doesn't have one.
+/// Quantiles cover the last ten minutes, sum and count all observations.
+#[derive(Clone)]
+pub struct SummaryVec {
+    desc: Desc,
+    quantiles: Vec<f64>,
+    series: Arc<Mutex<HashMap<Vec<String>, Series>>>,
+}
+
+impl SummaryVec {
+    pub fn new(opts: Opts, labels: &[&str], quantiles: Vec<f64>) -> prometheus::Result<Self> {
+        let desc = Desc::new(
+            opts.fq_name(),
+            opts.help,
+            labels.iter().map(|l| l.to_string()).collect(),
+            opts.const_labels,
+        )?;
+        let quantiles = match quantiles.is_empty() {
+            true => DEFAULT_QUANTILES.to_vec(),
+            false => quantiles,
+        };
+        Ok(SummaryVec {
+            desc,
+            quantiles,
+            series: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn observe(&self, label_vals: &[&str], value: f64) {
+        let labels = label_vals.iter().map(|l| l.to_string()).collect();
+        let mut series = self.series.lock().unwrap();
+        let series = series.entry(labels).or_default();
+        // without scrapes `collect` never runs, expire here as well
+        let now = Instant::now();
+        series.expire(now);
+        series.observations.push_back((now, value));
+        series.sum += value;
+        series.count += 1;
+    }
+
+    pub fn remove_label_values(&self, label_vals: &[&str]) {
+        let labels: Vec<String> = label_vals.iter().map(|l| l.to_string()).collect();
+        self.series.lock().unwrap().remove(&labels);
+    }
+
+    fn metric(&self, labels: &[String], series: &mut Series) -> proto::Metric {
+        series.expire(Instant::now());
+        let mut values: Vec<f64> = series.observations.iter().map(|(_, v)| *v).collect();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        let quantiles: Vec<_> = self
+            .quantiles
+            .iter()
+            .map(|q| {
+                let mut quantile = proto::Quantile::default();
+                quantile.set_quantile(*q);
+                quantile.set_value(nearest_rank(&values, *q));
+                quantile
+            })
+            .collect();
+        let mut summary = proto::Summary::default();
+        summary.set_sample_count(series.count);
+        summary.set_sample_sum(series.sum);
+        summary.set_quantile(quantiles.into());
+
+        let pairs: Vec<_> = self
+            .desc
+            .variable_labels
+            .iter()
+            .zip(labels)
+            .map(|(name, value)| {
+                let mut pair = proto::LabelPair::default();
+                pair.set_name(name.clone());
+                pair.set_value(value.clone());
+                pair
+            })
+            .collect();
+        let mut metric = proto::Metric::default();
+        metric.set_label(pairs.into());
+        metric.set_summary(summary);
+        metric
+    }
+}
+
+/// The value below which `q` of the sorted `values` fall, NaN without any.
+fn nearest_rank(values: &[f64], q: f64) -> f64 {
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    let rank = (q * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+impl Collector for SummaryVec {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<proto::MetricFamily> {
+        let mut series = self.series.lock().unwrap();
+        let metrics: Vec<_> = series
+            .iter_mut()
+            .map(|(labels, series)| self.metric(labels, series))
+            .collect();
+        let mut family = proto::MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(proto::MetricType::SUMMARY);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_SummaryVec_reports_quantiles() {
+        let summary = SummaryVec::new(
+            Opts::new("duration_seconds", "help"),
+            &["path"],
+            vec![0.5, 0.9],
+        )
+        .unwrap();
+        for v in 1..=10 {
+            summary.observe(&["/bin/true"], v as f64);
+        }
+
+        let families = summary.collect();
+        let metric = &families[0].get_metric()[0];
+        assert_eq!(metric.get_label()[0].get_value(), "/bin/true");
+        let s = metric.get_summary();
+        assert_eq!(s.get_sample_count(), 10);
+        assert_eq!(s.get_sample_sum(), 55.0);
+        let quantiles: Vec<_> = s.get_quantile().iter().map(|q| q.get_value()).collect();
+        assert_eq!(quantiles, vec![5.0, 9.0]);
+
+        summary.remove_label_values(&["/bin/true"]);
+        assert!(summary.collect()[0].get_metric().is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::config::file::DerivedConfig;
+use crate::msg::{MetricSpec, SensorReading, SetupMetrics, Value};
+use log::{error, info};
+use uuid::Uuid;
+use xactor::*;
+
+// Magnus formula coefficients over water, valid from -45°C to 60°C
+const MAGNUS_B: f64 = 17.62;
+const MAGNUS_C: f64 = 243.12;
+
+pub fn dew_point(temperature: f64, humidity: f64) -> f64 {
+    let gamma = (humidity / 100.0).ln() + (MAGNUS_B * temperature) / (MAGNUS_C + temperature);
+    MAGNUS_C * gamma / (MAGNUS_B - gamma)
+}
+
+/// Water vapour in g/m³.
+pub fn absolute_humidity(temperature: f64, humidity: f64) -> f64 {
+    let saturation_hpa = 6.112 * ((17.67 * temperature) / (temperature + 243.5)).exp();
+    saturation_hpa * humidity * 2.1674 / (273.15 + temperature)
+}
+
+/// The NOAA heat index (Rothfusz regression with Steadman's simple formula below 80°F).
+pub fn heat_index(temperature: f64, humidity: f64) -> f64 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+    let mut hi = 0.5 * (t + 61.0 + ((t - 68.0) * 1.2) + (rh * 0.094));
+    if (hi + t) / 2.0 >= 80.0 {
+        hi = -42.379 + 2.04901523 * t + 10.14333127 * rh
+            - 0.22475541 * t * rh
+            - 0.00683783 * t * t
+            - 0.05481717 * rh * rh
+            + 0.00122874 * t * t * rh
+            + 0.00085282 * t * rh * rh
+            - 0.00000199 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= ((13.0 - rh) / 4.0) * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += ((rh - 85.0) / 10.0) * ((87.0 - t) / 5.0);
+        }
+    }
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+/// Reduces the station pressure to sea level using the barometric formula.
+pub fn sea_level_pressure(pressure: f64, temperature: f64, altitude: f64) -> f64 {
+    pressure * (1.0 - (0.0065 * altitude) / (temperature + 0.0065 * altitude + 273.15)).powf(-5.257)
+}
+
+/// Converts pressure readings in `pa` or `kpa`, anything else is taken as hPa.
+fn pressure_hpa(value: f64, unit: &str) -> f64 {
+    match unit.to_ascii_lowercase().as_str() {
+        "pa" => value / 100.0,
+        "kpa" => value * 10.0,
+        _ => value,
+    }
+}
+
+#[derive(Default)]
+struct Latest {
+    temperature: Option<f64>,
+    humidity: Option<f64>,
+    pressure: Option<f64>,
+}
+
+struct Source {
+    derived_id: Uuid,
+    // pressure that's already at sea level isn't reduced again
+    sea_level_pressure: bool,
+    // keyed by the label values after kind and unit
+    latest: HashMap<Vec<String>, Latest>,
+}
+
+/// Listens to gauges with `kind` and `unit` labels and publishes psychrometric
+/// values for their temperature, humidity and pressure readings on a
+/// `<name>_derived` gauge.
+pub(crate) struct DerivedMetrics {
+    altitude: Option<f64>,
+    sources: HashMap<Uuid, Source>,
+    own: HashSet<Uuid>,
+}
+
+impl DerivedMetrics {
+    pub fn new(config: &DerivedConfig) -> Self {
+        DerivedMetrics {
+            altitude: config.altitude_m,
+            sources: HashMap::new(),
+            own: HashSet::new(),
+        }
+    }
+}
+
+fn derive(latest: &Latest, altitude: Option<f64>) -> Vec<(&'static str, &'static str, f64)> {
+    let mut values = vec![];
+    if let (Some(t), Some(h)) = (latest.temperature, latest.humidity) {
+        if h > 0.0 {
+            values.push(("dew_point", "celsius", dew_point(t, h)));
+        }
+        values.push(("absolute_humidity", "gpm3", absolute_humidity(t, h)));
+        values.push(("heat_index", "celsius", heat_index(t, h)));
+    }
+    if let (Some(p), Some(t), Some(alt)) = (latest.pressure, latest.temperature, altitude) {
+        values.push(("sea_level_pressure", "hpa", sea_level_pressure(p, t, alt)));
+    }
+    values
+}
+
+#[async_trait::async_trait]
+impl Actor for DerivedMetrics {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.subscribe::<SetupMetrics>().await?;
+        ctx.subscribe::<SensorReading>().await?;
+        info!("Derived metrics active");
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<SetupMetrics> for DerivedMetrics {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SetupMetrics) {
+        if let SetupMetrics::Gauge(spec) = msg {
+            let labels = spec.labels;
+            let has_kind_unit = labels.len() >= 2 && labels[0] == "kind" && labels[1] == "unit";
+            if self.own.contains(&spec.id) || !has_kind_unit {
+                return;
+            }
+            let derived_id = Uuid::new_v4();
+            self.own.insert(derived_id);
+            self.sources.insert(
+                spec.id,
+                Source {
+                    derived_id,
+                    sea_level_pressure: spec.sea_level_pressure,
+                    latest: HashMap::new(),
+                },
+            );
+            match Broker::from_registry().await {
+                Ok(mut addr) => {
+                    let derived =
+                        MetricSpec::new(derived_id, format!("{}_derived", spec.name), labels)
+                            .help(format!("Values derived from {}", spec.help));
+                    let derived = MetricSpec {
+                        namespace: spec.namespace,
+                        subsystem: spec.subsystem,
+                        interval: spec.interval,
+                        ..derived
+                    };
+                    if let Err(e) = addr.publish(SetupMetrics::Gauge(derived)) {
+                        error!("Couldn't set up derived metrics for '{}': {}", spec.name, e);
+                    }
+                }
+                Err(e) => error!("Broker unavailable: {}", e),
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<SensorReading> for DerivedMetrics {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SensorReading) {
+        let value = match msg.reading {
+            Value::Simple(v) => v as f64,
+            _ => return,
+        };
+        if msg.labels.len() < 2 {
+            return;
+        }
+        let source = match self.sources.get_mut(&msg.id) {
+            Some(source) => source,
+            None => return,
+        };
+        let latest = source.latest.entry(msg.labels[2..].to_vec()).or_default();
+        match msg.labels[0].as_str() {
+            "temperature" => latest.temperature = Some(value),
+            "humidity" => latest.humidity = Some(value),
+            "pressure" if !source.sea_level_pressure => {
+                latest.pressure = Some(pressure_hpa(value, &msg.labels[1]))
+            }
+            _ => return,
+        }
+
+        let derived_id = source.derived_id;
+        let readings: Vec<_> = derive(latest, self.altitude)
+            .into_iter()
+            .map(|(kind, unit, v)| SensorReading {
+                id: derived_id,
+                reading: Value::Simple(v as f32),
+                labels: [kind.to_string(), unit.to_string()]
+                    .iter()
+                    .chain(msg.labels[2..].iter())
+                    .cloned()
+                    .collect(),
+                timestamp: msg.timestamp,
+            })
+            .collect();
+
+        if let Ok(mut addr) = Broker::from_registry().await {
+            for reading in readings {
+                let _ = addr.publish(reading);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_dew_point_and_absolute_humidity() {
+        assert_close(dew_point(20.0, 50.0), 9.26);
+        assert_close(absolute_humidity(20.0, 50.0), 8.64);
+    }
+
+    #[test]
+    fn test_heat_index_uses_regression_when_hot() {
+        assert_close(heat_index(20.0, 50.0), 19.36);
+        assert_close(heat_index(32.0, 70.0), 40.41);
+    }
+
+    #[test]
+    fn test_sea_level_pressure_at_altitude() {
+        assert_close(sea_level_pressure(1000.0, 15.0, 0.0), 1000.0);
+        assert_close(sea_level_pressure(1000.0, 15.0, 100.0), 1011.92);
+    }
+}
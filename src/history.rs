@@ -0,0 +1,306 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::file::HistoryConfig;
+use crate::msg::{SensorReading, SetupMetrics, Value};
+use log::info;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use xactor::*;
+
+/// Query parameters of `/history`, times are in seconds since the epoch.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HistoryQuery {
+    /// Name of the collector, e.g. `roomA`
+    pub metric: String,
+    /// Only series with this `kind` label
+    pub kind: Option<String>,
+    /// Defaults to an hour ago
+    pub from: Option<f64>,
+    /// Defaults to now
+    pub to: Option<f64>,
+    /// Average readings into buckets of this many seconds, returns raw readings if unset
+    pub step: Option<f64>,
+}
+
+#[message(result = "Vec<HistorySeries>")]
+pub struct QueryHistory(pub HistoryQuery);
+
+#[message]
+#[derive(Clone, Debug)]
+struct Prune;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HistorySeries {
+    pub metric: String,
+    pub labels: BTreeMap<String, String>,
+    /// `[seconds since the epoch, value]`
+    pub points: Vec<(f64, f64)>,
+}
+
+struct Series {
+    labels: BTreeMap<String, String>,
+    // milliseconds since the epoch
+    points: VecDeque<(i64, f64)>,
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Averages the points between `from` and `to` into buckets of `step`
+/// milliseconds, each timestamped with the bucket's start. A `step` of 0 only
+/// filters the points.
+pub fn downsample(points: &VecDeque<(i64, f64)>, from: i64, to: i64, step: i64) -> Vec<(i64, f64)> {
+    let in_range = points.iter().filter(|(ts, _)| *ts >= from && *ts <= to);
+    if step <= 0 {
+        return in_range.cloned().collect();
+    }
+    let mut buckets: BTreeMap<i64, (f64, usize)> = BTreeMap::new();
+    for (ts, v) in in_range {
+        let bucket = buckets.entry(from + (ts - from) / step * step).or_default();
+        bucket.0 += v;
+        bucket.1 += 1;
+    }
+    buckets
+        .into_iter()
+        .map(|(ts, (sum, n))| (ts, sum / n as f64))
+        .collect()
+}
+
+/// Keeps a ring buffer of recent readings per series, counters are stored as
+/// their running total.
+pub(crate) struct HistoryStore {
+    retention_ms: i64,
+    max_points: usize,
+    collectors: HashMap<Uuid, (String, Vec<String>)>,
+    series: HashMap<(Uuid, Vec<String>), Series>,
+}
+
+impl HistoryStore {
+    pub fn new(config: &HistoryConfig) -> Self {
+        HistoryStore {
+            retention_ms: config.retention_s as i64 * 1000,
+            max_points: config.max_points.max(1),
+            collectors: HashMap::new(),
+            series: HashMap::new(),
+        }
+    }
+
+    fn record(&mut self, msg: SensorReading, at: i64) {
+        let SensorReading {
+            id,
+            reading,
+            labels,
+            ..
+        } = msg;
+        let label_names = match self.collectors.get(&id) {
+            Some((_, names)) => names,
+            None => return,
+        };
+        let series = self
+            .series
+            .entry((id, labels.clone()))
+            .or_insert_with(|| Series {
+                labels: label_names.iter().cloned().zip(labels).collect(),
+                points: VecDeque::new(),
+            });
+        // queries expect the points in order
+        if matches!(series.points.back(), Some((ts, _)) if *ts > at) {
+            return;
+        }
+        let last = series.points.back().map(|(_, v)| *v).unwrap_or_default();
+        let value = match reading {
+            Value::Simple(v) => v as f64,
+            Value::Inc => last + 1.0,
+            Value::IncBy(v) => last + v as f64,
+            Value::Dec => last - 1.0,
+            Value::Observe(_) => return,
+        };
+        series.points.push_back((at, value));
+
+        let oldest = at - self.retention_ms;
+        while series.points.len() > self.max_points
+            || matches!(series.points.front(), Some((ts, _)) if *ts < oldest)
+        {
+            series.points.pop_front();
+        }
+    }
+
+    /// Drops readings older than the retention from every series, and series
+    /// whose source stopped reporting altogether.
+    fn prune(&mut self, now: i64) {
+        let oldest = now - self.retention_ms;
+        self.series.retain(|_, series| {
+            while matches!(series.points.front(), Some((ts, _)) if *ts < oldest) {
+                series.points.pop_front();
+            }
+            !series.points.is_empty()
+        });
+    }
+
+    fn query(&self, query: &HistoryQuery) -> Vec<HistorySeries> {
+        let to = query.to.map(|s| (s * 1000.0) as i64).unwrap_or_else(now_ms);
+        let from = query
+            .from
+            .map(|s| (s * 1000.0) as i64)
+            .unwrap_or(to - 60 * 60 * 1000);
+        let step = query.step.map(|s| (s * 1000.0) as i64).unwrap_or(0);
+
+        let mut result: Vec<_> = self
+            .series
+            .iter()
+            .filter(|((id, _), _)| {
+                matches!(self.collectors.get(id), Some((name, _)) if *name == query.metric)
+            })
+            .filter(|(_, s)| match &query.kind {
+                Some(kind) => s.labels.get("kind") == Some(kind),
+                None => true,
+            })
+            .map(|(_, s)| HistorySeries {
+                metric: query.metric.clone(),
+                labels: s.labels.clone(),
+                points: downsample(&s.points, from, to, step)
+                    .into_iter()
+                    .map(|(ts, v)| (ts as f64 / 1000.0, v))
+                    .collect(),
+            })
+            .collect();
+        result.sort_by(|a, b| a.labels.cmp(&b.labels));
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for HistoryStore {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.subscribe::<SetupMetrics>().await?;
+        ctx.subscribe::<SensorReading>().await?;
+        ctx.send_interval(Prune, Duration::from_secs(60));
+        info!(
+            "Keeping up to {} readings per series for {}s",
+            self.max_points,
+            self.retention_ms / 1000
+        );
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<SetupMetrics> for HistoryStore {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SetupMetrics) {
+        let spec = match msg {
+            SetupMetrics::Gauge(spec) | SetupMetrics::Counter(spec) => spec,
+            SetupMetrics::Histogram(..) | SetupMetrics::Summary(..) => return,
+        };
+        self.collectors.insert(spec.id, (spec.name, spec.labels));
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<SensorReading> for HistoryStore {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SensorReading) {
+        let at = msg.timestamp.unwrap_or_else(now_ms);
+        self.record(msg, at);
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<QueryHistory> for HistoryStore {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: QueryHistory) -> Vec<HistorySeries> {
+        self.prune(now_ms());
+        self.query(&msg.0)
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<Prune> for HistoryStore {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Prune) {
+        self.prune(now_ms());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use crate::msg::MetricSpec;
+
+    fn reading(id: Uuid, kind: &str, reading: Value) -> SensorReading {
+        SensorReading {
+            id,
+            reading,
+            labels: vec![kind.into(), "celsius".into()],
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_downsample_averages_buckets() {
+        let points: VecDeque<_> = vec![(0, 1.0), (500, 3.0), (1000, 5.0), (2500, 7.0), (4000, 9.0)]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            downsample(&points, 0, 3000, 1000),
+            vec![(0, 2.0), (1000, 5.0), (2000, 7.0)]
+        );
+        assert_eq!(
+            downsample(&points, 900, 2500, 0),
+            vec![(1000, 5.0), (2500, 7.0)]
+        );
+    }
+
+    #[test]
+    fn test_HistoryStore_keeps_bounded_series() {
+        let mut store = HistoryStore::new(&HistoryConfig {
+            enabled: true,
+            retention_s: 10,
+            max_points: 3,
+        });
+        let id = Uuid::new_v4();
+        let spec = MetricSpec::new(id, "roomA", vec!["kind".into(), "unit".into()]);
+        store.collectors.insert(id, (spec.name, spec.labels));
+
+        for (i, v) in [20.0, 21.0, 22.0, 23.0].iter().enumerate() {
+            store.record(
+                reading(id, "temperature", Value::Simple(*v)),
+                i as i64 * 1000,
+            );
+        }
+        store.record(reading(id, "humidity", Value::Simple(40.0)), 20_000);
+
+        let temperature = store.query(&HistoryQuery {
+            metric: "roomA".into(),
+            kind: Some("temperature".into()),
+            from: Some(0.0),
+            to: Some(30.0),
+            step: None,
+        });
+        assert_eq!(temperature.len(), 1);
+        assert_eq!(temperature[0].labels["unit"], "celsius");
+        assert_eq!(
+            temperature[0].points,
+            vec![(1.0, 21.0), (2.0, 22.0), (3.0, 23.0)]
+        );
+
+        // the second humidity reading pushes the first one out of the retention window
+        store.record(reading(id, "humidity", Value::IncBy(2.0)), 31_000);
+        let humidity = store.query(&HistoryQuery {
+            metric: "roomA".into(),
+            kind: Some("humidity".into()),
+            from: Some(0.0),
+            to: Some(40.0),
+            step: None,
+        });
+        assert_eq!(humidity[0].points, vec![(31.0, 42.0)]);
+
+        // temperature stopped reporting, pruning drops it
+        store.prune(31_000);
+        assert_eq!(store.series.len(), 1);
+    }
+}
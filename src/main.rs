@@ -1,22 +1,26 @@
 use anyhow::Result;
 mod config;
 mod db;
+mod derived;
+mod history;
 mod msg;
 mod sensors;
+mod sinks;
 mod utils;
 
 mod router;
 #[cfg(feature = "switch-gpio")]
 mod switches;
-use clap::App as ClApp;
+use clap::{App as ClApp, Arg};
 
 use config::Config;
 use db::PrometheusCollector;
+use derived::DerivedMetrics;
+use history::{HistoryQuery, HistoryStore, QueryHistory};
 
 use log::info;
 use msg::EncodeData;
 
-use envconfig::Envconfig;
 use tide::{Body, Request};
 use tide::{Response, StatusCode}; // Pulls in the json! macro.
 use xactor::{Actor, Addr};
@@ -30,6 +34,7 @@ use crate::utils::e_;
 #[derive(Clone)]
 pub struct AppState {
     collector: CollectorAddr,
+    history: Option<Addr<HistoryStore>>,
 }
 
 async fn metrics(req: Request<AppState>) -> tide::Result {
@@ -40,23 +45,76 @@ async fn metrics(req: Request<AppState>) -> tide::Result {
     Ok(resp)
 }
 
+async fn history(req: Request<AppState>) -> tide::Result {
+    let query: HistoryQuery = req.query()?;
+    match &req.state().history {
+        Some(history) => {
+            let series = history.call(QueryHistory(query)).await?;
+            Ok(Response::builder(StatusCode::Ok)
+                .body(Body::from_json(&series)?)
+                .build())
+        }
+        None => Ok(Response::new(StatusCode::NotFound)),
+    }
+}
+
 #[async_std::main]
 async fn main() -> Result<()> {
-    let _ = ClApp::new("jotunheim")
+    let matches = ClApp::new("jotunheim")
         .version("0.1.0")
         .author("Claus Matzinger. <claus.matzinger+kb@gmail.com>")
         .about("A no-fluff sensor reader.")
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("TOML configuration file, JH_* environment variables take precedence"),
+        )
         .get_matches();
 
     env_logger::init();
-    let config: Config = Config::init_from_env()?;
+    let config: Config = Config::load(matches.value_of("config"))?;
 
     info!("Welcome to Jotunheim.");
-    let prometheus = PrometheusCollector::new()?.start().await?;
+    let prometheus = PrometheusCollector::new(
+        config.file.namespace.clone(),
+        config.file.stale_after_intervals.unwrap_or(3),
+    )?
+    .start()
+    .await?;
+
+    let _derived = if config.file.derived.enabled {
+        Some(DerivedMetrics::new(&config.file.derived).start().await?)
+    } else {
+        None
+    };
+
+    #[cfg(feature = "sink-remote-write")]
+    let _remote_write = sinks::remote_write::setup(&config, prometheus.clone()).await?;
+
+    #[cfg(feature = "sink-pushgateway")]
+    let _pushgateway = sinks::pushgateway::setup(&config, prometheus.clone()).await?;
+
+    #[cfg(feature = "sink-influx")]
+    let _influx = sinks::influx::setup(&config).await?;
+
+    #[cfg(feature = "sink-mqtt")]
+    let _mqtt = sinks::mqtt::setup(&config).await?;
+
+    let history_store = if config.file.history.enabled {
+        Some(HistoryStore::new(&config.file.history).start().await?)
+    } else {
+        None
+    };
 
     #[cfg(feature = "sensor-external")]
     let _external_actors = external::setup(&config).await?;
 
+    #[cfg(feature = "sensor-scrape")]
+    let _scrapers = sensors::scrape::setup(&config).await?;
+
     #[cfg(feature = "sensor-bme680")]
     let _bme = sensors::bme680::setup(&config).await?;
 
@@ -68,8 +126,10 @@ async fn main() -> Result<()> {
 
     let mut app = tide::with_state(AppState {
         collector: prometheus,
+        history: history_store,
     });
     app.at("/metrics").get(metrics);
+    app.at("/history").get(history);
 
     #[cfg(feature = "sensor-mqtt-heater")]
     app.at("/r").nest(router::register_actors(vec![hf]).await?);
@@ -6,20 +6,105 @@ pub(crate) struct SensorReading {
     pub id: uuid::Uuid,
     pub reading: Value,
     pub labels: Vec<String>,
+    /// Milliseconds since the epoch when the source took the reading, if it knows
+    pub timestamp: Option<i64>,
 }
 
 #[derive(Clone, Debug)]
 pub(crate) enum Value {
     Simple(f32),
     Inc,
+    IncBy(f32),
     Dec,
+    Observe(f64),
+}
+
+/// Describes a collector, its full name is `<namespace>_<subsystem>_<name>`.
+#[derive(Clone, Debug)]
+pub(crate) struct MetricSpec {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub labels: Vec<String>,
+    pub help: String,
+    pub namespace: Option<String>,
+    pub subsystem: Option<String>,
+    /// Base unit like `seconds` or `celsius`, part of the exported name
+    pub unit: Option<String>,
+    /// Its pressure readings are already reduced to sea level
+    pub sea_level_pressure: bool,
+    /// How often the collector is updated, label sets expire if they miss a few updates
+    pub interval: Option<std::time::Duration>,
+}
+
+impl MetricSpec {
+    pub fn new<N: Into<String>>(id: uuid::Uuid, name: N, labels: Vec<String>) -> Self {
+        let name = name.into();
+        MetricSpec {
+            id,
+            help: name.clone(),
+            name,
+            labels,
+            namespace: None,
+            subsystem: None,
+            unit: None,
+            sea_level_pressure: false,
+            interval: None,
+        }
+    }
+
+    pub fn help<H: Into<String>>(mut self, help: H) -> Self {
+        self.help = help.into();
+        self
+    }
+
+    pub fn subsystem<S: Into<String>>(mut self, subsystem: S) -> Self {
+        self.subsystem = Some(subsystem.into());
+        self
+    }
+
+    pub fn unit<U: Into<String>>(mut self, unit: U) -> Self {
+        self.unit = Some(unit.into());
+        self
+    }
+
+    /// The name with `_<unit>` appended unless it already ends with it,
+    /// `_total` stays last, e.g. `energy_total` becomes `energy_joules_total`.
+    pub fn name_with_unit(&self) -> String {
+        let unit = match &self.unit {
+            Some(unit) => unit,
+            None => return self.name.clone(),
+        };
+        let (base, total) = match self.name.strip_suffix("_total") {
+            Some(base) => (base, "_total"),
+            None => (self.name.as_str(), ""),
+        };
+        if base.ends_with(&format!("_{}", unit)) {
+            self.name.clone()
+        } else {
+            format!("{}_{}{}", base, unit, total)
+        }
+    }
+
+    pub fn sea_level_pressure(mut self) -> Self {
+        self.sea_level_pressure = true;
+        self
+    }
+
+    pub fn interval(mut self, interval: std::time::Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
 }
 
 #[message]
 #[derive(Clone, Debug)]
 pub(crate) enum SetupMetrics {
-    Gauge(uuid::Uuid, String, Vec<String>),
-    Counter(uuid::Uuid, String, Vec<String>),
+    Gauge(MetricSpec),
+    Counter(MetricSpec),
+    /// Bucket upper bounds, Prometheus' defaults if empty
+    Histogram(MetricSpec, Vec<f64>),
+    /// Quantiles over the last ten minutes, 0.5, 0.9 and 0.99 if empty
+    Summary(MetricSpec, Vec<f64>),
 }
 
 #[message]
@@ -32,13 +117,31 @@ pub(crate) struct SwitchState;
 #[message(result = "anyhow::Result<String>")]
 pub(crate) struct EncodeData;
 
+#[message(result = "Vec<prometheus::proto::MetricFamily>")]
+pub(crate) struct Gather;
+
 #[message(result = "anyhow::Result<()>")]
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) enum Switch {
     On,
     Off,
 }
 
+/// A switch was commanded to a new state.
+#[message]
+#[derive(Clone, Debug)]
+pub(crate) struct SwitchChanged {
+    pub name: String,
+    pub on: bool,
+}
+
+/// Announces a switch, e.g. for MQTT discovery.
+#[message]
+#[derive(Clone, Debug)]
+pub(crate) struct SetupSwitch {
+    pub name: String,
+}
+
 #[message(result = "anyhow::Result<()>")]
 #[derive(Clone, Debug)]
 pub struct DeviceControl {
@@ -4,6 +4,9 @@ pub mod bme680;
 #[cfg(feature = "sensor-external")]
 pub mod external;
 
+#[cfg(feature = "sensor-scrape")]
+pub mod scrape;
+
 #[cfg(feature = "sensor-api")]
 pub mod api;
 
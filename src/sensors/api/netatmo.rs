@@ -5,7 +5,7 @@ use crate::{
     utils::{avg, max},
 };
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use async_std::task;
 use core::time::Duration;
 use futures_util::{join, FutureExt};
@@ -14,7 +14,7 @@ use serde_json;
 use uuid::Uuid;
 use xactor::*;
 
-use crate::msg::{SensorReading, SetupMetrics};
+use crate::msg::{MetricSpec, SensorReading, SetupMetrics};
 use serde::{Deserialize, Serialize};
 
 const AUTH_URL: &str = "https://api.netatmo.com/oauth2/token";
@@ -168,9 +168,15 @@ impl Actor for NetatmoSensorReader {
         let mut addr = Broker::from_registry().await?;
 
         addr.publish(SetupMetrics::Gauge(
-            self.collector_id,
-            "netatmo".into(),
-            vec![String::from("kind"), String::from("unit")],
+            MetricSpec::new(
+                self.collector_id,
+                "netatmo",
+                vec![String::from("kind"), String::from("unit")],
+            )
+            .help("Weather readings from nearby Netatmo stations")
+            .interval(self.resolution)
+            // the dashboard's `Pressure`, `AbsolutePressure` is the station's
+            .sea_level_pressure(),
         ))?;
 
         ctx.send_interval(IntervalMessage::Read, self.resolution);
@@ -248,6 +254,7 @@ impl Handler<IntervalMessage> for NetatmoSensorReader {
                                             id: collector_id,
                                             reading: Value::Simple(max(&wind) as f32),
                                             labels: vec![String::from("wind"), String::from("kph")],
+                                            timestamp: None,
                                         })
                                         .unwrap();
 
@@ -258,6 +265,7 @@ impl Handler<IntervalMessage> for NetatmoSensorReader {
                                                 String::from("rain"),
                                                 String::from("mmph"),
                                             ],
+                                            timestamp: None,
                                         })
                                         .unwrap();
                                     }
@@ -283,6 +291,8 @@ impl Handler<IntervalMessage> for NetatmoSensorReader {
                                 match response {
                                     Ok(response) => {
                                         let data = &response.body["devices"][0]["dashboard_data"];
+                                        // seconds since the epoch when the station measured
+                                        let timestamp = data["time_utc"].as_i64().map(|s| s * 1000);
                                         let readings = vec![
                                             SensorReading {
                                                 id: collector_id,
@@ -293,16 +303,18 @@ impl Handler<IntervalMessage> for NetatmoSensorReader {
                                                     String::from("temperature"),
                                                     String::from("celsius"),
                                                 ],
+                                                timestamp,
                                             },
                                             SensorReading {
                                                 id: collector_id,
                                                 reading: Value::Simple(
                                                     data["Pressure"].as_f64().unwrap() as f32,
-                                                ), // AbsolutePressure ?
+                                                ),
                                                 labels: vec![
                                                     String::from("pressure"),
                                                     String::from("hpa"),
                                                 ],
+                                                timestamp,
                                             },
                                             SensorReading {
                                                 id: collector_id,
@@ -313,6 +325,7 @@ impl Handler<IntervalMessage> for NetatmoSensorReader {
                                                     String::from("humidity"),
                                                     String::from("percent"),
                                                 ],
+                                                timestamp,
                                             },
                                             SensorReading {
                                                 id: collector_id,
@@ -323,6 +336,7 @@ impl Handler<IntervalMessage> for NetatmoSensorReader {
                                                     String::from("co2"),
                                                     String::from("ppm"),
                                                 ],
+                                                timestamp,
                                             },
                                         ];
                                         for reading in readings {
@@ -360,11 +374,23 @@ impl Handler<IntervalMessage> for NetatmoSensorReader {
 pub async fn setup(config: &Config) -> Result<Addr<NetatmoSensorReader>> {
     //"id|user|password|clientid|secret"
     info!("Setting up netatmo sensor");
-    let all_creds = config.parsed_credentials().await?;
-    let raw_creds = all_creds
-        .get("netatmo")
-        .ok_or(anyhow!("No netatmo credentials found"))?;
-    let (device_id, parsed_credentials) = parse(&raw_creds)?;
+    let env_creds = match config.api_credentials {
+        Some(_) => config.parsed_credentials().await?.remove("netatmo"),
+        None => None,
+    };
+    let (device_id, parsed_credentials) = match (env_creds, &config.file.netatmo) {
+        (Some(raw_creds), _) => parse(&raw_creds)?,
+        (None, Some(n)) => (
+            n.device_id.clone(),
+            NetatmoSingleAuth::with_scope_read_station(
+                n.client_id.clone(),
+                n.client_secret.clone(),
+                n.username.clone(),
+                n.password.clone(),
+            ),
+        ),
+        (None, None) => bail!("No netatmo credentials found"),
+    };
     NetatmoSensorReader::new(
         device_id,
         parsed_credentials,
@@ -1,15 +1,29 @@
-use crate::{config::Config, msg::Value};
+mod iaq;
+
+use crate::{
+    config::{
+        file::{
+            Bme680Address, Bme680Config, Bme680Settings, IaqConfig, InstanceConfig, Labels,
+            Oversampling,
+        },
+        Config,
+    },
+    msg::Value,
+    utils::{label_names, label_values},
+};
 use bme680::*;
 use core::time::Duration;
 use hal::I2cdev;
 use linux_embedded_hal as hal;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use uuid::Uuid;
 use xactor::*;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+
+use crate::msg::{MetricSpec, ReadNow, SensorReading, SetupMetrics};
 
-use crate::msg::{ReadNow, SensorReading, SetupMetrics};
+use self::iaq::IaqCalculator;
 
 struct AsyncDelay {}
 
@@ -20,134 +34,387 @@ impl embedded_hal::blocking::delay::DelayMs<u8> for AsyncDelay {
     }
 }
 
-pub fn is_available(path: &str) -> bool {
+fn i2c_address(address: Bme680Address) -> I2CAddress {
+    match address {
+        Bme680Address::Primary => I2CAddress::Primary,
+        Bme680Address::Secondary => I2CAddress::Secondary,
+    }
+}
+
+fn oversampling(setting: Oversampling) -> OversamplingSetting {
+    match setting {
+        Oversampling::None => OversamplingSetting::OSNone,
+        Oversampling::X1 => OversamplingSetting::OS1x,
+        Oversampling::X2 => OversamplingSetting::OS2x,
+        Oversampling::X4 => OversamplingSetting::OS4x,
+        Oversampling::X8 => OversamplingSetting::OS8x,
+        Oversampling::X16 => OversamplingSetting::OS16x,
+    }
+}
+
+fn filter_size(size: u8) -> Result<IIRFilterSize> {
+    Ok(match size {
+        0 => IIRFilterSize::Size0,
+        1 => IIRFilterSize::Size1,
+        3 => IIRFilterSize::Size3,
+        7 => IIRFilterSize::Size7,
+        15 => IIRFilterSize::Size15,
+        31 => IIRFilterSize::Size31,
+        63 => IIRFilterSize::Size63,
+        127 => IIRFilterSize::Size127,
+        other => bail!("Invalid BME680 filter size: {}", other),
+    })
+}
+
+fn build_settings(config: &Bme680Settings) -> Result<Settings> {
+    let mut builder = SettingsBuilder::new()
+        .with_humidity_oversampling(oversampling(config.humidity_oversampling))
+        .with_pressure_oversampling(oversampling(config.pressure_oversampling))
+        .with_temperature_oversampling(oversampling(config.temperature_oversampling))
+        .with_temperature_filter(filter_size(config.filter_size)?)
+        .with_temperature_offset(config.temperature_offset)
+        .with_run_gas(config.gas.enabled);
+    if config.gas.enabled {
+        builder = builder.with_gas_measurement(
+            Duration::from_millis(config.gas.duration_ms),
+            config.gas.temperature_celsius,
+            config.gas.ambient_celsius,
+        );
+    }
+    Ok(builder.build())
+}
+
+pub fn is_available(path: &str, address: Bme680Address) -> bool {
     if let Ok(d) = I2cdev::new(path) {
-        Bme680::init(d, &mut AsyncDelay {}, I2CAddress::Primary).is_ok()
+        Bme680::init(d, &mut AsyncDelay {}, i2c_address(address)).is_ok()
     } else {
         false
     }
 }
 
+/// Consecutive read failures after which the device is opened again.
+const REINIT_AFTER: u32 = 3;
+/// First retry delay after a failed read, doubled with every further failure.
+const RETRY_BASE: Duration = Duration::from_millis(500);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+fn init_device(path: &str, address: Bme680Address) -> Result<Bme680<I2cdev, AsyncDelay>> {
+    let i2c = I2cdev::new(path)?;
+    Bme680::init(i2c, &mut AsyncDelay {}, i2c_address(address))
+        .map_err(|e| anyhow!("No I2C device found: {:?}", e))
+}
+
 pub struct Bme680SensorReader {
     dev: Bme680<I2cdev, AsyncDelay>,
+    path: String,
+    address: Bme680Address,
     collector_id: Uuid,
+    error_collector_id: Uuid,
     resolution: Duration,
     name: String,
+    labels: Labels,
+    settings: Settings,
+    run_gas: bool,
+    profile_dur: Duration,
+    iaq: Option<IaqCalculator>,
+    // reads configure the device first until this succeeds
+    configured: bool,
+    failures: u32,
 }
 
 // "/dev/i2c-1"
 impl Bme680SensorReader {
-    pub fn new<I: Into<String>>(path: &str, name: I, resolution: Duration) -> Result<Self> {
-        let i2c = I2cdev::new(path)?;
-        match Bme680::init(i2c, &mut AsyncDelay {}, I2CAddress::Primary) {
-            Ok(dev) => {
-                let collector_id = Uuid::new_v4();
-                Ok(Bme680SensorReader {
-                    dev,
-                    collector_id,
-                    resolution,
-                    name: name.into(),
-                })
-            }
-            Err(_) => bail!("No I2C devicen found"),
+    pub fn new<I: Into<String>>(
+        path: &str,
+        address: Bme680Address,
+        name: I,
+        resolution: Duration,
+        labels: Labels,
+        settings: &Bme680Settings,
+        iaq: IaqConfig,
+    ) -> Result<Self> {
+        let run_gas = settings.gas.enabled;
+        if iaq.enabled && !run_gas {
+            warn!("IAQ needs the gas heater enabled, skipping it for {}", path);
         }
+        let iaq = if iaq.enabled && run_gas {
+            Some(IaqCalculator::new(iaq))
+        } else {
+            None
+        };
+        let settings = build_settings(settings)?;
+        let dev = init_device(path, address)?;
+        Ok(Bme680SensorReader {
+            dev,
+            path: path.to_string(),
+            address,
+            collector_id: Uuid::new_v4(),
+            error_collector_id: Uuid::new_v4(),
+            resolution,
+            name: name.into(),
+            labels,
+            settings,
+            run_gas,
+            profile_dur: Duration::default(),
+            iaq,
+            configured: false,
+            failures: 0,
+        })
     }
-}
-
-#[async_trait::async_trait]
-impl Actor for Bme680SensorReader {
-    async fn started(&mut self, ctx: &mut Context<Self>) -> anyhow::Result<()> {
-        let mut addr = Broker::from_registry().await?;
-        addr.publish(SetupMetrics::Gauge(
-            self.collector_id,
-            self.name.clone(),
-            vec![String::from("kind"), String::from("unit")],
-        ))?;
 
-        ctx.send_interval(ReadNow, self.resolution);
-        info!("BME680 reader set up");
-        Ok(())
+    fn reading_labels(&self, kind: &str, unit: &str) -> Vec<String> {
+        label_values(vec![kind.to_string(), unit.to_string()], &self.labels)
     }
-}
 
-#[async_trait::async_trait]
-impl Handler<ReadNow> for Bme680SensorReader {
-    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: ReadNow) {
-        let settings = SettingsBuilder::new()
-            .with_humidity_oversampling(OversamplingSetting::OS2x)
-            .with_pressure_oversampling(OversamplingSetting::OS4x)
-            .with_temperature_oversampling(OversamplingSetting::OS8x)
-            .with_temperature_filter(IIRFilterSize::Size3)
-            .with_gas_measurement(Duration::from_millis(1500), 320, 25)
-            .with_temperature_offset(-2.2)
-            .with_run_gas(true)
-            .build();
-
-        let profile_dur = self.dev.get_profile_dur(&settings.0).unwrap();
-
-        info!("Profile duration {:?}", profile_dur);
+    /// Writes the measurement settings, required after every (re-)initialisation.
+    fn configure(&mut self) -> Result<()> {
+        self.profile_dur = self
+            .dev
+            .get_profile_dur(&self.settings.0)
+            .map_err(|e| anyhow!("Couldn't calculate profile duration: {:?}", e))?;
+        info!("Profile duration {:?}", self.profile_dur);
         info!("Setting sensor settings");
         self.dev
-            .set_sensor_settings(&mut AsyncDelay {}, settings)
-            .unwrap();
-        info!("Setting forced power modes");
-        self.dev
-            .set_sensor_mode(&mut AsyncDelay {}, PowerMode::ForcedMode)
-            .unwrap();
-
-        let sensor_settings = self.dev.get_sensor_settings(settings.1);
+            .set_sensor_settings(&mut AsyncDelay {}, self.settings)
+            .map_err(|e| anyhow!("Couldn't apply sensor settings: {:?}", e))?;
+        let sensor_settings = self.dev.get_sensor_settings(self.settings.1);
         info!("Sensor settings: {:?}", sensor_settings);
+        self.configured = true;
+        Ok(())
+    }
+
+    fn reinit(&mut self) -> Result<()> {
+        info!(
+            "Re-initialising BME680 at {} ({:?})",
+            self.path, self.address
+        );
+        self.configured = false;
+        self.dev = init_device(&self.path, self.address)?;
+        self.configure()
+    }
 
+    async fn read(&mut self) -> Result<FieldData> {
+        if !self.configured {
+            self.configure()?;
+        }
         let power_mode = self.dev.get_sensor_mode();
-        info!("Sensor power mode: {:?}", power_mode);
-        info!("Setting forced power modes");
+        debug!("Sensor power mode: {:?}", power_mode);
+        debug!("Setting forced power modes");
         self.dev
             .set_sensor_mode(&mut AsyncDelay {}, PowerMode::ForcedMode)
-            .unwrap();
+            .map_err(|e| anyhow!("Couldn't set forced mode: {:?}", e))?;
+        async_std::task::sleep(self.profile_dur).await;
         info!("Retrieving sensor data");
-        let (data, _state) = self.dev.get_sensor_data(&mut AsyncDelay {}).unwrap();
+        let (data, _state) = self
+            .dev
+            .get_sensor_data(&mut AsyncDelay {})
+            .map_err(|e| anyhow!("Couldn't read sensor data: {:?}", e))?;
+        Ok(data)
+    }
+
+    fn readings(&mut self, data: FieldData) -> Vec<SensorReading> {
         info!("Sensor Data {:?}", data);
         info!("Temperature {}°C", data.temperature_celsius());
         info!("Pressure {}hPa", data.pressure_hpa());
         info!("Humidity {}%", data.humidity_percent());
         info!("Gas Resistence {}Ω", data.gas_resistance_ohm());
-        let readings = vec![
+        let mut readings = vec![
             SensorReading {
                 id: self.collector_id,
                 reading: Value::Simple(data.temperature_celsius()),
-                labels: vec![String::from("temperature"), String::from("celsius")],
+                labels: self.reading_labels("temperature", "celsius"),
+                timestamp: None,
             },
             SensorReading {
                 id: self.collector_id,
                 reading: Value::Simple(data.pressure_hpa()),
-                labels: vec![String::from("pressure"), String::from("hpa")],
+                labels: self.reading_labels("pressure", "hpa"),
+                timestamp: None,
             },
             SensorReading {
                 id: self.collector_id,
                 reading: Value::Simple(data.humidity_percent()),
-                labels: vec![String::from("humidity"), String::from("percent")],
+                labels: self.reading_labels("humidity", "percent"),
+                timestamp: None,
             },
-            SensorReading {
+        ];
+        if self.run_gas {
+            readings.push(SensorReading {
                 id: self.collector_id,
                 reading: Value::Simple(data.gas_resistance_ohm() as f32),
-                labels: vec![String::from("gas_resistance"), String::from("ohm")],
-            },
-        ];
+                labels: self.reading_labels("gas_resistance", "ohm"),
+                timestamp: None,
+            });
+        }
+        // the baseline mustn't follow readings taken before the heater settled
+        let gas_usable = data.gas_valid() && data.heat_stable();
+        if self.iaq.is_some() && !gas_usable {
+            debug!("Gas reading not usable yet, skipping IAQ");
+        }
+        let iaq = self.iaq.as_mut().filter(|_| gas_usable).and_then(|iaq| {
+            iaq.update(
+                data.gas_resistance_ohm() as f64,
+                data.humidity_percent() as f64,
+            )
+        });
+        if let Some(iaq) = iaq {
+            info!("IAQ {}", iaq);
+            readings.push(SensorReading {
+                id: self.collector_id,
+                reading: Value::Simple(iaq as f32),
+                labels: self.reading_labels("iaq", "index"),
+                timestamp: None,
+            });
+        }
+        readings
+    }
 
-        let mut addr = Broker::from_registry().await.unwrap();
-        for reading in readings {
-            addr.publish(reading).unwrap();
+    fn error_reading(&self, stage: &str) -> SensorReading {
+        SensorReading {
+            id: self.error_collector_id,
+            reading: Value::Inc,
+            labels: label_values(vec![stage.to_string()], &self.labels),
+            timestamp: None,
+        }
+    }
+
+    /// Delay until the next read: the resolution if all is well, an
+    /// exponential backoff while reads keep failing.
+    fn next_read(&self) -> Duration {
+        if self.failures == 0 {
+            self.resolution
+        } else {
+            let backoff = RETRY_BASE * 2_u32.saturating_pow(self.failures - 1);
+            backoff.min(RETRY_MAX)
         }
     }
 }
 
-pub async fn setup(config: &Config) -> Result<Addr<Bme680SensorReader>> {
-    let i2c_path = &config.bme680;
-    if is_available(i2c_path) {
-        Bme680SensorReader::new(i2c_path, &config.metrics_name, config.resolution())?
-            .start()
-            .await
+#[async_trait::async_trait]
+impl Actor for Bme680SensorReader {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> anyhow::Result<()> {
+        if let Err(e) = self.configure() {
+            // retried by the reads, failing like any other read
+            warn!("Couldn't configure BME680 at {}: {}", self.path, e);
+        }
+
+        let mut addr = Broker::from_registry().await?;
+        addr.publish(SetupMetrics::Gauge(
+            MetricSpec::new(
+                self.collector_id,
+                self.name.clone(),
+                label_names(&["kind", "unit"], &self.labels),
+            )
+            .help(format!("BME680 readings from {}", self.path))
+            .interval(self.resolution),
+        ))?;
+        addr.publish(SetupMetrics::Counter(
+            MetricSpec::new(
+                self.error_collector_id,
+                format!("{}_errors_total", self.name),
+                label_names(&["stage"], &self.labels),
+            )
+            .help(format!("Failed BME680 operations on {}", self.path)),
+        ))?;
+
+        ctx.send_later(ReadNow, self.resolution);
+        info!("BME680 reader set up");
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<ReadNow> for Bme680SensorReader {
+    async fn handle(&mut self, ctx: &mut Context<Self>, _msg: ReadNow) {
+        let readings = match self.read().await {
+            Ok(data) => {
+                self.failures = 0;
+                self.readings(data)
+            }
+            Err(e) => {
+                self.failures += 1;
+                error!(
+                    "BME680 at {} failed {} time(s): {}",
+                    self.path, self.failures, e
+                );
+                let mut readings = vec![self.error_reading("read")];
+                if self.failures % REINIT_AFTER == 0 {
+                    if let Err(e) = self.reinit() {
+                        error!("Couldn't re-initialise BME680 at {}: {}", self.path, e);
+                        readings.push(self.error_reading("init"));
+                    }
+                }
+                readings
+            }
+        };
+
+        ctx.send_later(ReadNow, self.next_read());
+        match Broker::from_registry().await {
+            Ok(mut addr) => {
+                for reading in readings {
+                    if let Err(e) = addr.publish(reading) {
+                        error!("Couldn't publish BME680 reading: {}", e);
+                    }
+                }
+            }
+            Err(e) => error!("Broker unavailable: {}", e),
+        }
+    }
+}
+
+/// Unnamed sensors get the bus and address appended to the global name
+/// when there is more than one, e.g. `roomA_i2c_1_secondary`.
+fn default_name(metrics_name: &str, sensor: &Bme680Config, multiple: bool) -> String {
+    if multiple {
+        let bus = sensor.path.rsplit('/').next().unwrap_or(&sensor.path);
+        let address = match sensor.address {
+            Bme680Address::Primary => "primary",
+            Bme680Address::Secondary => "secondary",
+        };
+        sensor.name_or(&format!(
+            "{}_{}_{}",
+            metrics_name,
+            bus.replace('-', "_"),
+            address
+        ))
     } else {
-        bail!("BME680 not found at {}", i2c_path);
+        sensor.name_or(metrics_name)
+    }
+}
+
+pub async fn setup(config: &Config) -> Result<Vec<Addr<Bme680SensorReader>>> {
+    let sensors = config.bme680_sensors();
+    let multiple = sensors.len() > 1;
+    let mut bme_actors = vec![];
+    for sensor in sensors {
+        // one missing sensor shouldn't take the others down
+        if !is_available(&sensor.path, sensor.address) {
+            error!("BME680 not found at {} ({:?})", sensor.path, sensor.address);
+            continue;
+        }
+        let actor = Bme680SensorReader::new(
+            &sensor.path,
+            sensor.address,
+            default_name(&config.metrics_name, &sensor, multiple),
+            sensor.resolution_or(config.resolution()),
+            sensor.labels.clone(),
+            &sensor.settings,
+            sensor.iaq.clone(),
+        );
+        match actor {
+            Ok(actor) => match actor.start().await {
+                Ok(addr) => bme_actors.push(addr),
+                Err(e) => error!(
+                    "Couldn't start the BME680 reader for {} ({:?}): {}",
+                    sensor.path, sensor.address, e
+                ),
+            },
+            Err(e) => error!(
+                "Couldn't set up the BME680 at {} ({:?}): {}",
+                sensor.path, sensor.address, e
+            ),
+        }
     }
+    info!("{} BME680 sensor(s) active", bme_actors.len());
+    Ok(bme_actors)
 }
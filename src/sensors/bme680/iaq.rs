@@ -0,0 +1,147 @@
+use crate::{config::file::IaqConfig, utils::avg};
+use log::{error, info};
+
+/// Number of burn-in readings averaged into the initial baseline.
+const BASELINE_WINDOW: usize = 50;
+/// How fast the baseline follows a cleaner (higher resistance) reading.
+const BASELINE_ADAPTION: f64 = 0.05;
+/// Write the baseline to disk every this many readings.
+const PERSIST_EVERY: usize = 100;
+
+/// Derives an indoor air quality index (0 = excellent, 500 = hazardous) from
+/// gas resistance and humidity, relative to a baseline of "clean air" resistance.
+pub struct IaqCalculator {
+    config: IaqConfig,
+    burn_in: Vec<f64>,
+    baseline: Option<f64>,
+    since_persist: usize,
+}
+
+impl IaqCalculator {
+    pub fn new(config: IaqConfig) -> Self {
+        let baseline = config.baseline_path.as_ref().and_then(|p| {
+            std::fs::read_to_string(p)
+                .map_err(|e| info!("No IAQ baseline at '{}': {}", p, e))
+                .ok()
+                .and_then(|s| s.trim().parse::<f64>().ok())
+        });
+        if let Some(b) = baseline {
+            info!("Restored IAQ baseline of {}Ω", b);
+        }
+        IaqCalculator {
+            config,
+            burn_in: vec![],
+            baseline,
+            since_persist: 0,
+        }
+    }
+
+    pub fn baseline(&self) -> Option<f64> {
+        self.baseline
+    }
+
+    /// Adds a reading and returns the IAQ index once the baseline is known.
+    pub fn update(&mut self, gas_ohm: f64, humidity: f64) -> Option<f64> {
+        let baseline = match self.baseline {
+            Some(b) if gas_ohm > b => {
+                let b = b + (gas_ohm - b) * BASELINE_ADAPTION;
+                self.baseline = Some(b);
+                b
+            }
+            Some(b) => b,
+            None => {
+                self.burn_in.push(gas_ohm);
+                if self.burn_in.len() < self.config.burn_in_samples.max(1) {
+                    return None;
+                }
+                let window = self.burn_in.len().saturating_sub(BASELINE_WINDOW);
+                let b = avg(&self.burn_in[window..]);
+                info!("IAQ burn-in complete, baseline is {}Ω", b);
+                self.burn_in.clear();
+                self.baseline = Some(b);
+                self.persist();
+                b
+            }
+        };
+
+        self.since_persist += 1;
+        if self.since_persist >= PERSIST_EVERY {
+            self.persist();
+        }
+        Some(self.score(gas_ohm, humidity, baseline))
+    }
+
+    fn score(&self, gas_ohm: f64, humidity: f64, baseline: f64) -> f64 {
+        let hum_baseline = self.config.humidity_baseline;
+        let hum_weight = self.config.humidity_weighting * 100.0;
+        let gas_weight = 100.0 - hum_weight;
+
+        let hum_offset = humidity - hum_baseline;
+        let hum_score = if hum_offset > 0.0 {
+            (100.0 - hum_baseline - hum_offset) / (100.0 - hum_baseline) * hum_weight
+        } else {
+            (hum_baseline + hum_offset) / hum_baseline * hum_weight
+        };
+
+        let gas_score = if gas_ohm < baseline {
+            gas_ohm / baseline * gas_weight
+        } else {
+            gas_weight
+        };
+
+        // 100 is the best score, scale and invert it to the 0-500 IAQ range
+        (100.0 - (hum_score + gas_score).clamp(0.0, 100.0)) * 5.0
+    }
+
+    fn persist(&mut self) {
+        self.since_persist = 0;
+        if let (Some(path), Some(b)) = (&self.config.baseline_path, self.baseline) {
+            if let Err(e) = std::fs::write(path, b.to_string()) {
+                error!("Couldn't persist IAQ baseline to '{}': {}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    fn calculator(burn_in_samples: usize) -> IaqCalculator {
+        IaqCalculator::new(IaqConfig {
+            burn_in_samples,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_IaqCalculator_waits_for_burn_in() {
+        let mut iaq = calculator(3);
+        assert_eq!(iaq.update(100_000.0, 40.0), None);
+        assert_eq!(iaq.update(100_000.0, 40.0), None);
+        assert_eq!(iaq.update(100_000.0, 40.0), Some(0.0));
+        assert_eq!(iaq.baseline(), Some(100_000.0));
+    }
+
+    #[test]
+    fn test_IaqCalculator_scores_polluted_air_higher() {
+        let mut iaq = calculator(1);
+        iaq.update(100_000.0, 40.0);
+        let clean = iaq.update(100_000.0, 40.0).unwrap();
+        let polluted = iaq.update(25_000.0, 40.0).unwrap();
+        let humid = iaq.update(100_000.0, 80.0).unwrap();
+
+        assert_eq!(clean, 0.0);
+        assert!((polluted - 281.25).abs() < 1e-9);
+        assert!(humid > clean);
+    }
+
+    #[test]
+    fn test_IaqCalculator_baseline_follows_cleaner_air() {
+        let mut iaq = calculator(1);
+        iaq.update(100_000.0, 40.0);
+        iaq.update(200_000.0, 40.0);
+        assert_eq!(iaq.baseline(), Some(105_000.0));
+    }
+}
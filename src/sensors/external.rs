@@ -1,41 +1,341 @@
-use crate::{config::Config, msg::Value};
-use async_std::task;
+pub(crate) mod collectors;
+pub(crate) mod parsers;
+pub(crate) mod reading;
+
+use crate::{
+    config::{
+        file::{ExternalConfig, ExternalMode, InstanceConfig, Labels},
+        Config,
+    },
+    msg::Value,
+    utils::{label_names, label_values},
+};
+use async_std::{
+    future::timeout,
+    io::BufReader,
+    prelude::*,
+    process::{Command, Stdio},
+    task::{self, JoinHandle},
+};
 use core::time::Duration;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde_json;
-use std::process::{Command, Stdio};
+use std::{collections::HashMap, fmt, mem, path::Path, time::Instant};
 use uuid::Uuid;
 use xactor::*;
 
-use crate::msg::{ReadNow, SensorReading, SetupMetrics};
+use crate::msg::{MetricSpec, ReadNow, SensorReading, SetupMetrics};
+
+use self::collectors::{Collectors, SourceMetrics};
+use self::parsers::Parser;
+use self::reading::{ExternalReading, MetricType};
 
-use serde::{Deserialize, Serialize};
+/// First restart delay of a streaming command, doubled while it keeps exiting.
+const RESTART_BASE: Duration = Duration::from_secs(1);
+const RESTART_MAX: Duration = Duration::from_secs(60);
 
-#[derive(Serialize, Deserialize, Default)]
-struct ExternalReading {
-    value: f64,
-    kind: String,
-    unit: String,
+#[message]
+enum StreamEvent {
+    /// A line, or a block of lines for parsers that aren't line oriented
+    Output(String),
+    Failed(ExecError),
 }
 
-pub struct ExternalSensorReader {
+#[derive(Debug)]
+enum ExecError {
+    Spawn(std::io::Error),
+    Timeout(Duration),
+    Exit(Option<i32>),
+    Output(anyhow::Error),
+}
+
+impl ExecError {
+    fn reason(&self) -> &'static str {
+        match self {
+            ExecError::Spawn(_) => "spawn",
+            ExecError::Timeout(_) => "timeout",
+            ExecError::Exit(_) => "exit_code",
+            ExecError::Output(_) => "output",
+        }
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::Spawn(e) => write!(f, "couldn't be started: {}", e),
+            ExecError::Timeout(t) => write!(f, "was killed after {:?}", t),
+            ExecError::Exit(code) => write!(f, "returned a non-zero exit code: {:?}", code),
+            ExecError::Output(e) => write!(f, "returned invalid output: {}", e),
+        }
+    }
+}
+
+/// Everything needed to start an external command.
+#[derive(Clone, Debug)]
+struct CommandSpec {
     path: String,
     args: Vec<String>,
+    env: Vec<(String, String)>,
+    cwd: Option<String>,
+}
+
+impl CommandSpec {
+    fn from_config(config: &ExternalConfig) -> Self {
+        let mut env: Vec<(String, String)> = config
+            .env_passthrough
+            .iter()
+            .filter(|k| !config.env.contains_key(*k))
+            .filter_map(|k| std::env::var(k).ok().map(|v| (k.clone(), v)))
+            .collect();
+        env.extend(config.env.clone());
+        CommandSpec {
+            path: config.path.clone(),
+            args: config.args.clone(),
+            env,
+            cwd: config.cwd.clone(),
+        }
+    }
+
+    /// A command with nothing but the configured environment, stdout and stderr piped.
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.path);
+        cmd.args(&self.args)
+            .env_clear()
+            .envs(self.env.iter().cloned())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        cmd
+    }
+}
+
+pub struct ExternalSensorReader {
+    command: CommandSpec,
     resolution: Duration,
+    timeout: Duration,
     collector_id: Uuid,
     name: String,
+    labels: Labels,
+    metrics: SourceMetrics,
+    mode: ExternalMode,
+    parser: Parser,
+    stream_task: Option<JoinHandle<()>>,
+    /// Collectors besides the main gauge, for readings with a metric type or extra labels
+    collectors: Collectors,
+    timestamps: HashMap<(Uuid, Vec<String>), i64>,
 }
 
 impl ExternalSensorReader {
-    pub fn new<I: Into<String>>(path: I, name: I, args: Vec<String>, resolution: Duration) -> Self {
+    pub fn new<I: Into<String>>(
+        config: ExternalConfig,
+        name: I,
+        resolution: Duration,
+        metrics: SourceMetrics,
+    ) -> Self {
         let collector_id = Uuid::new_v4();
+        let name = name.into();
+        let interval = match config.mode {
+            ExternalMode::Poll => Some(resolution),
+            ExternalMode::Stream => None,
+        };
         ExternalSensorReader {
-            path: path.into(),
-            args,
+            collectors: Collectors::new(
+                name.clone(),
+                config.path.clone(),
+                label_names(&["unit"], &config.labels),
+                interval,
+            ),
+            timeout: config
+                .timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(resolution),
+            command: CommandSpec::from_config(&config),
+            parser: Parser::from_config(&config),
             collector_id,
             resolution,
-            name: name.into(),
+            name,
+            labels: config.labels,
+            metrics,
+            mode: config.mode,
+            stream_task: None,
+            timestamps: HashMap::new(),
+        }
+    }
+
+    async fn execute(&self) -> std::result::Result<String, ExecError> {
+        debug!("Starting execution with {}", self.command.path);
+        let output = self.command.command().output();
+        // the child is killed when the output future is dropped
+        let output = timeout(self.timeout, output)
+            .await
+            .map_err(|_| ExecError::Timeout(self.timeout))?
+            .map_err(ExecError::Spawn)?;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.trim().is_empty() {
+            warn!("'{}' wrote to stderr: {}", self.command.path, stderr.trim());
+        }
+        if output.status.success() {
+            String::from_utf8(output.stdout).map_err(|e| ExecError::Output(e.into()))
+        } else {
+            Err(ExecError::Exit(output.status.code()))
+        }
+    }
+
+    fn parse(&mut self, output: &str) -> std::result::Result<Vec<SensorReading>, ExecError> {
+        let ext_values = self.parser.parse(output).map_err(ExecError::Output)?;
+        Ok(ext_values
+            .into_iter()
+            .filter_map(|v| self.to_reading(v))
+            .collect())
+    }
+
+    fn to_reading(&mut self, v: ExternalReading) -> Option<SensorReading> {
+        let (id, labels) = if v.is_simple() {
+            let labels = label_values(vec![v.kind.clone(), v.unit.clone()], &self.labels);
+            (self.collector_id, labels)
+        } else {
+            let extra: Labels = v
+                .labels
+                .iter()
+                .filter(|(k, _)| k.as_str() != "unit" && !self.labels.contains_key(*k))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            let id = self.collectors.collector_for(
+                v.metric_type != MetricType::Gauge,
+                &v.kind,
+                v.help.as_ref(),
+                extra.keys().cloned().collect(),
+            );
+            let mut labels = label_values(vec![v.unit.clone()], &self.labels);
+            labels.extend(extra.into_values());
+            (id, labels)
+        };
+
+        if let Some(ts) = v.timestamp {
+            let last = self
+                .timestamps
+                .entry((id, labels.clone()))
+                .or_insert(i64::MIN);
+            if ts <= *last {
+                debug!("Skipping out of order '{}' reading from {}", v.kind, ts);
+                return None;
+            }
+            *last = ts;
+        }
+
+        let reading = match v.metric_type {
+            MetricType::Gauge => Value::Simple(v.value as f32),
+            _ if v.value < 0.0 => {
+                warn!("Ignoring negative '{}' counter value {}", v.kind, v.value);
+                return None;
+            }
+            MetricType::Increment => Value::IncBy(v.value as f32),
+            MetricType::Counter => {
+                Value::IncBy(self.collectors.increase(id, &labels, v.value) as f32)
+            }
+        };
+        Some(SensorReading {
+            id,
+            reading,
+            labels,
+            timestamp: v.timestamp,
+        })
+    }
+
+    /// Streams report whenever they like, only polled readings can go stale.
+    fn with_interval(&self, spec: MetricSpec) -> MetricSpec {
+        match self.mode {
+            ExternalMode::Poll => spec.interval(self.resolution),
+            ExternalMode::Stream => spec,
+        }
+    }
+
+    fn error_reading(&self, e: &ExecError) -> SensorReading {
+        error!("Command '{}' {}", self.command.path, e);
+        self.metrics.error(vec![
+            self.name.clone(),
+            self.command.path.clone(),
+            e.reason().to_string(),
+        ])
+    }
+}
+
+/// Sends `event` to the reader, false once it has stopped.
+fn forward(addr: &WeakAddr<ExternalSensorReader>, event: StreamEvent) -> bool {
+    addr.upgrade()
+        .map_or(false, |addr| addr.send(event).is_ok())
+}
+
+/// Keeps a streaming command running and forwards each line of its stdout,
+/// or with `blocks` everything up to a blank line or the end of the output,
+/// restarting it with an exponential backoff whenever it exits. It only holds
+/// a weak address, so the reader can stop and cancel it.
+async fn stream(addr: WeakAddr<ExternalSensorReader>, command: CommandSpec, blocks: bool) {
+    let path = &command.path;
+    let mut backoff = RESTART_BASE;
+    loop {
+        let spawned = command.command().spawn();
+        let failure = match spawned {
+            Ok(mut child) => {
+                info!("Streaming from '{}' (pid {})", path, child.id());
+                if let Some(stderr) = child.stderr.take() {
+                    let path = path.clone();
+                    task::spawn(async move {
+                        let mut lines = BufReader::new(stderr).lines();
+                        while let Some(Ok(line)) = lines.next().await {
+                            warn!("'{}' wrote to stderr: {}", path, line);
+                        }
+                    });
+                }
+                if let Some(stdout) = child.stdout.take() {
+                    let mut lines = BufReader::new(stdout).lines();
+                    let mut block = String::new();
+                    loop {
+                        let output = match lines.next().await {
+                            Some(Ok(line)) if line.trim().is_empty() => mem::take(&mut block),
+                            Some(Ok(line)) if blocks => {
+                                block.push_str(&line);
+                                block.push('\n');
+                                continue;
+                            }
+                            Some(Ok(line)) => line,
+                            Some(Err(e)) => {
+                                error!("Couldn't read from '{}': {}", path, e);
+                                break;
+                            }
+                            // the last block may not end with a blank line
+                            None if !block.is_empty() => mem::take(&mut block),
+                            None => break,
+                        };
+                        if output.is_empty() {
+                            continue;
+                        }
+                        backoff = RESTART_BASE;
+                        if !forward(&addr, StreamEvent::Output(output)) {
+                            return;
+                        }
+                    }
+                }
+                let _ = child.kill();
+                match child.status().await {
+                    Ok(status) => ExecError::Exit(status.code()),
+                    Err(e) => ExecError::Spawn(e),
+                }
+            }
+            Err(e) => ExecError::Spawn(e),
+        };
+        warn!("'{}' stopped, restarting in {:?}", path, backoff);
+        if !forward(&addr, StreamEvent::Failed(failure)) {
+            return;
         }
+        task::sleep(backoff).await;
+        backoff = (backoff * 2).min(RESTART_MAX);
     }
 }
 
@@ -44,79 +344,122 @@ impl Actor for ExternalSensorReader {
     async fn started(&mut self, ctx: &mut Context<Self>) -> anyhow::Result<()> {
         let mut addr = Broker::from_registry().await?;
         addr.publish(SetupMetrics::Gauge(
-            self.collector_id,
-            self.name.clone(),
-            vec![String::from("kind"), String::from("unit")],
+            self.with_interval(
+                MetricSpec::new(
+                    self.collector_id,
+                    self.name.clone(),
+                    label_names(&["kind", "unit"], &self.labels),
+                )
+                .help(format!("Readings from {}", self.command.path)),
+            ),
         ))?;
 
-        ctx.send_interval(ReadNow, self.resolution);
-        info!("External reader for path '{}' set up", self.path);
-        debug!(
-            "Expecting JSON cmd line output like: {}",
-            serde_json::to_string(&ExternalReading::default()).unwrap()
-        );
+        match self.mode {
+            ExternalMode::Poll => ctx.send_interval(ReadNow, self.resolution),
+            ExternalMode::Stream => {
+                let blocks = !self.parser.is_line_oriented();
+                self.stream_task = Some(task::spawn(stream(
+                    ctx.address().downgrade(),
+                    self.command.clone(),
+                    blocks,
+                )));
+            }
+        }
+        info!("External reader for path '{}' set up", self.command.path);
+        if self.parser == Parser::Json {
+            debug!(
+                "Expecting JSON cmd line output like: {}",
+                serde_json::to_string(&ExternalReading::default()).unwrap()
+            );
+        }
         Ok(())
     }
+
+    async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        if let Some(stream_task) = self.stream_task.take() {
+            stream_task.cancel().await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<StreamEvent> for ExternalSensorReader {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: StreamEvent) {
+        let readings = match msg {
+            StreamEvent::Output(output) => match self.parse(&output) {
+                Ok(readings) => readings,
+                Err(e) => vec![self.error_reading(&e)],
+            },
+            StreamEvent::Failed(e) => vec![self.error_reading(&e)],
+        };
+        self.collectors.publish(readings).await;
+    }
 }
 
 #[async_trait::async_trait]
 impl Handler<ReadNow> for ExternalSensorReader {
     async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: ReadNow) {
-        let exe = self.path.clone();
-        let default_args = self.args.clone();
-        debug!("Starting execution with {}", exe);
-        let output: String = task::spawn_blocking(move || -> anyhow::Result<String> {
-            let child = Command::new(&*exe)
-                .args(default_args)
-                .env_clear()
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect("failed to execute child");
-
-            let output = child.wait_with_output().expect("failed to wait on child");
-            if output.status.success() {
-                std::str::from_utf8(&output.stdout)
-                    .map(|s| s.to_owned())
-                    .map_err(|e| e.into())
-            } else {
-                error!(
-                    "Command '{}' returned a non-zero exit code: {:?}",
-                    exe,
-                    output.status.code()
-                );
-                Err(std::io::Error::from(std::io::ErrorKind::NotFound).into())
-            }
-        })
-        .await
-        .expect("Command didn't complete successfully.");
-
-        let ext_values: Vec<ExternalReading> =
-            serde_json::from_str(&output).expect("No valid JSON was returned");
+        let started = Instant::now();
+        let result = self.execute().await;
+        let mut readings = vec![self.metrics.duration(
+            vec![self.name.clone(), self.command.path.clone()],
+            started.elapsed(),
+        )];
 
-        let readings = ext_values.into_iter().map(|v| SensorReading {
-            reading: Value::Simple(v.value as f32),
-            id: self.collector_id,
-            labels: vec![v.kind, v.unit],
-        });
-
-        let mut addr = Broker::from_registry().await.unwrap();
-        for reading in readings {
-            addr.publish(reading).unwrap();
+        match result.and_then(|output| self.parse(&output)) {
+            Ok(values) => readings.extend(values),
+            Err(e) => readings.push(self.error_reading(&e)),
         }
+        self.collectors.publish(readings).await;
+    }
+}
+
+/// Unnamed externals get the file stem of their command appended to the
+/// global name when there is more than one, e.g. `roomA_co2`.
+fn default_name(metrics_name: &str, external: &ExternalConfig, multiple: bool) -> String {
+    if multiple {
+        let stem = Path::new(&external.path)
+            .file_stem()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default();
+        external.name_or(&format!("{}_{}", metrics_name, stem))
+    } else {
+        external.name_or(metrics_name)
     }
 }
 
 pub async fn setup(config: &Config) -> Result<Vec<Addr<ExternalSensorReader>>> {
     let mut external_actors = vec![];
-    let externals = config.parsed_externals().await;
+    let externals = config.external_sensors().await;
+    let multiple = externals.len() > 1;
 
     if !externals.is_empty() {
         info!(
             "External Sensor module active, {} paths found",
             externals.len()
         );
-        for actor in externals.into_iter().map(|p| {
-            ExternalSensorReader::new(&p, &config.metrics_name, vec![], config.resolution())
+        let metrics = SourceMetrics::setup(
+            "external",
+            &["name", "path", "reason"],
+            "Failed runs of external sensor commands",
+        )
+        .await?;
+        // streams run for good, only polled commands have a duration
+        if externals.iter().any(|e| e.mode == ExternalMode::Poll) {
+            metrics
+                .setup_duration(
+                    "external",
+                    &["name", "path"],
+                    "How long an external sensor command takes to run",
+                    &config.file.external_duration,
+                )
+                .await?;
+        }
+
+        for actor in externals.into_iter().map(|e| {
+            let name = default_name(&config.metrics_name, &e, multiple);
+            let resolution = e.resolution_or(config.resolution());
+            ExternalSensorReader::new(e, name, resolution, metrics)
         }) {
             let a = actor.start().await?;
             external_actors.push(a);
@@ -126,3 +469,21 @@ pub async fn setup(config: &Config) -> Result<Vec<Addr<ExternalSensorReader>>> {
         Ok(vec![])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_default_name_tells_unnamed_externals_apart() {
+        let co2 = ExternalConfig::with_path("/opt/jotunheim/co2.py");
+        let named = ExternalConfig {
+            name: Some("lux".into()),
+            ..ExternalConfig::with_path("/opt/jotunheim/lux.sh")
+        };
+        assert_eq!(default_name("roomA", &co2, false), "roomA");
+        assert_eq!(default_name("roomA", &co2, true), "roomA_co2");
+        assert_eq!(default_name("roomA", &named, true), "lux");
+    }
+}
@@ -0,0 +1,237 @@
+use crate::config::file::DurationConfig;
+use crate::msg::{MetricSpec, SensorReading, SetupMetrics, Value};
+use anyhow::Result;
+use core::time::Duration;
+use log::{error, info};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+use xactor::*;
+
+/// Collectors are keyed by counter or not, kind and extra label names.
+type CollectorKey = (bool, String, Vec<String>);
+
+/// The errors and duration collectors shared by all sources of a module,
+/// registered once in its `setup`.
+#[derive(Clone, Copy, Debug)]
+pub struct SourceMetrics {
+    errors: Uuid,
+    duration: Uuid,
+}
+
+impl SourceMetrics {
+    /// Registers `<subsystem>_errors_total` labeled by `labels`.
+    pub async fn setup(subsystem: &str, labels: &[&str], help: &str) -> Result<Self> {
+        let metrics = SourceMetrics {
+            errors: Uuid::new_v4(),
+            duration: Uuid::new_v4(),
+        };
+        Broker::from_registry()
+            .await?
+            .publish(SetupMetrics::Counter(
+                MetricSpec::new(metrics.errors, "errors_total", label_names(labels))
+                    .subsystem(subsystem)
+                    .help(help),
+            ))?;
+        Ok(metrics)
+    }
+
+    /// Registers `<subsystem>_duration_seconds` labeled by `labels`, a summary
+    /// if quantiles are configured and a histogram otherwise.
+    pub async fn setup_duration(
+        &self,
+        subsystem: &str,
+        labels: &[&str],
+        help: &str,
+        config: &DurationConfig,
+    ) -> Result<()> {
+        let spec = MetricSpec::new(self.duration, "duration", label_names(labels))
+            .subsystem(subsystem)
+            .unit("seconds")
+            .help(help);
+        Broker::from_registry()
+            .await?
+            .publish(match config.quantiles.is_empty() {
+                true => SetupMetrics::Histogram(spec, config.buckets.clone()),
+                false => SetupMetrics::Summary(spec, config.quantiles.clone()),
+            })
+    }
+
+    pub fn error(&self, labels: Vec<String>) -> SensorReading {
+        SensorReading {
+            id: self.errors,
+            reading: Value::Inc,
+            labels,
+            timestamp: None,
+        }
+    }
+
+    pub fn duration(&self, labels: Vec<String>, elapsed: Duration) -> SensorReading {
+        SensorReading {
+            id: self.duration,
+            reading: Value::Observe(elapsed.as_secs_f64()),
+            labels,
+            timestamp: None,
+        }
+    }
+}
+
+fn label_names(labels: &[&str]) -> Vec<String> {
+    labels.iter().map(|l| l.to_string()).collect()
+}
+
+/// Registers a collector per kind and label set the first time a source
+/// reports it, so sources can return whatever series they like.
+pub struct Collectors {
+    name: String,
+    source: String,
+    label_names: Vec<String>,
+    interval: Option<Duration>,
+    ids: HashMap<CollectorKey, Uuid>,
+    names: HashSet<String>,
+    pending_setup: Vec<SetupMetrics>,
+    totals: HashMap<(Uuid, Vec<String>), f64>,
+}
+
+impl Collectors {
+    /// Collector names start with `name`, `source` is the command or URL the
+    /// readings come from and every collector has the `label_names` first.
+    /// Without an `interval` they never go stale.
+    pub fn new<I: Into<String>>(
+        name: I,
+        source: I,
+        label_names: Vec<String>,
+        interval: Option<Duration>,
+    ) -> Self {
+        Collectors {
+            name: name.into(),
+            source: source.into(),
+            label_names,
+            interval,
+            ids: HashMap::new(),
+            names: HashSet::new(),
+            pending_setup: vec![],
+            totals: HashMap::new(),
+        }
+    }
+
+    /// Finds or sets up the collector for `kind` with the `extra` labels,
+    /// counters are named `<name>_<kind>_total`.
+    pub fn collector_for(
+        &mut self,
+        counter: bool,
+        kind: &str,
+        help: Option<&String>,
+        extra: Vec<String>,
+    ) -> Uuid {
+        let key = (counter, kind.to_string(), extra);
+        if let Some(id) = self.ids.get(&key) {
+            return *id;
+        }
+
+        let kind = match counter {
+            true => kind.trim_end_matches("_total"),
+            false => kind,
+        };
+        let mut name = format!("{}_{}", self.name, kind);
+        if self.names.contains(&name) {
+            // same kind with other labels, names have to be unique per label set
+            name = format!("{}_by_{}", name, key.2.join("_"));
+        }
+        self.names.insert(name.clone());
+        let mut names = self.label_names.clone();
+        names.extend(key.2.iter().cloned());
+        info!("Registering '{}' for '{}'", name, self.source);
+
+        let id = Uuid::new_v4();
+        let help = help
+            .cloned()
+            .unwrap_or_else(|| format!("'{}' readings from {}", key.1, self.source));
+        let spec = if counter {
+            MetricSpec::new(id, format!("{}_total", name), names)
+        } else {
+            MetricSpec::new(id, name, names)
+        }
+        .help(help);
+        let spec = match self.interval {
+            Some(interval) => spec.interval(interval),
+            None => spec,
+        };
+        self.pending_setup.push(if counter {
+            SetupMetrics::Counter(spec)
+        } else {
+            SetupMetrics::Gauge(spec)
+        });
+        self.ids.insert(key, id);
+        id
+    }
+
+    /// The increase since the last `total` of a series, a lower total means
+    /// the source restarted counting.
+    pub fn increase(&mut self, id: Uuid, labels: &[String], total: f64) -> f64 {
+        match self.totals.insert((id, labels.to_vec()), total) {
+            Some(last) if total >= last => total - last,
+            _ => total,
+        }
+    }
+
+    /// Publishes the setup of new collectors ahead of the readings.
+    pub async fn publish(&mut self, readings: Vec<SensorReading>) {
+        match Broker::from_registry().await {
+            Ok(mut addr) => {
+                for setup in self.pending_setup.drain(..) {
+                    if let Err(e) = addr.publish(setup) {
+                        error!("Couldn't set up metrics of '{}': {}", self.source, e);
+                    }
+                }
+                for reading in readings {
+                    if let Err(e) = addr.publish(reading) {
+                        error!("Couldn't publish reading of '{}': {}", self.source, e);
+                    }
+                }
+            }
+            Err(e) => error!("Broker unavailable: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_Collectors_registers_each_label_set_once() {
+        let mut collectors = Collectors::new("roomA", "/bin/sensor", vec!["unit".into()], None);
+        let power = collectors.collector_for(false, "power", None, vec!["plug".into()]);
+        assert_eq!(
+            collectors.collector_for(false, "power", None, vec!["plug".into()]),
+            power
+        );
+        collectors.collector_for(false, "power", None, vec!["phase".into()]);
+        collectors.collector_for(true, "energy_total", None, vec![]);
+
+        let names: Vec<_> = collectors
+            .pending_setup
+            .iter()
+            .map(|s| match s {
+                SetupMetrics::Gauge(spec) | SetupMetrics::Counter(spec) => spec.name.clone(),
+                SetupMetrics::Histogram(spec, _) | SetupMetrics::Summary(spec, _) => {
+                    spec.name.clone()
+                }
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec!["roomA_power", "roomA_power_by_phase", "roomA_energy_total"]
+        );
+    }
+
+    #[test]
+    fn test_Collectors_increase_handles_resets() {
+        let mut collectors = Collectors::new("roomA", "/bin/sensor", vec!["unit".into()], None);
+        let (id, labels) = (Uuid::new_v4(), vec!["plug1".to_string()]);
+        assert_eq!(collectors.increase(id, &labels, 5.0), 5.0);
+        assert_eq!(collectors.increase(id, &labels, 7.0), 2.0);
+        assert_eq!(collectors.increase(id, &labels, 1.0), 1.0);
+    }
+}
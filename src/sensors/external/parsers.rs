@@ -0,0 +1,323 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, HashMap};
+
+use super::reading::{parse_json, ExternalReading, MetricType};
+use crate::config::file::{ExternalConfig, OutputFormat};
+
+/// Turns a command's output into readings according to its configured format.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Parser {
+    Json,
+    PrometheusText,
+    InfluxLine,
+    Plain { kind: String, unit: String },
+}
+
+impl Parser {
+    pub fn from_config(config: &ExternalConfig) -> Self {
+        match config.format {
+            OutputFormat::Json => Parser::Json,
+            OutputFormat::PrometheusText => Parser::PrometheusText,
+            OutputFormat::InfluxLine => Parser::InfluxLine,
+            OutputFormat::Plain => Parser::Plain {
+                kind: config.kind.clone().unwrap_or_else(|| "value".into()),
+                unit: config.unit.clone().unwrap_or_else(|| "none".into()),
+            },
+        }
+    }
+
+    /// The Prometheus format needs its `# TYPE` lines along with the samples,
+    /// streams send it in blocks ending with a blank line instead of line by line.
+    pub fn is_line_oriented(&self) -> bool {
+        *self != Parser::PrometheusText
+    }
+
+    pub fn parse(&self, output: &str) -> Result<Vec<ExternalReading>> {
+        match self {
+            Parser::Json => parse_json(output).map_err(From::from),
+            Parser::PrometheusText => parse_prometheus(output),
+            Parser::InfluxLine => parse_influx(output),
+            Parser::Plain { kind, unit } => parse_plain(output, kind, unit),
+        }
+    }
+}
+
+/// A single number, e.g. `21.5`.
+pub fn parse_plain(output: &str, kind: &str, unit: &str) -> Result<Vec<ExternalReading>> {
+    let value = output.trim().parse::<f64>()?;
+    Ok(vec![ExternalReading {
+        value,
+        kind: kind.to_string(),
+        unit: unit.to_string(),
+        ..Default::default()
+    }])
+}
+
+/// The Prometheus text exposition format. Counters keep their type, everything
+/// else becomes a gauge. The metric name is used as kind.
+pub fn parse_prometheus(output: &str) -> Result<Vec<ExternalReading>> {
+    let mut help = HashMap::new();
+    let mut types = HashMap::new();
+    let mut readings = vec![];
+    for line in output.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.trim_start().splitn(3, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("HELP"), Some(name), Some(text)) => {
+                    help.insert(name.to_string(), text.to_string());
+                }
+                (Some("TYPE"), Some(name), Some(t)) => {
+                    types.insert(name.to_string(), t.trim().to_string());
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        let (name, labels, rest) = match line.find('{') {
+            Some(start) => {
+                let (labels, len) = parse_prometheus_labels(&line[start + 1..])?;
+                (&line[..start], labels, &line[start + 1 + len..])
+            }
+            None => {
+                let end = line.find(char::is_whitespace).unwrap_or(line.len());
+                (&line[..end], BTreeMap::new(), &line[end..])
+            }
+        };
+        let mut fields = rest.split_whitespace();
+        let value = fields
+            .next()
+            .ok_or_else(|| anyhow!("No value for '{}'", name))?
+            .parse::<f64>()?;
+        let timestamp = fields.next().map(str::parse::<i64>).transpose()?;
+        let metric_type = match types.get(name.trim_end_matches("_total")) {
+            Some(t) if t == "counter" => MetricType::Counter,
+            _ => match types.get(name) {
+                Some(t) if t == "counter" => MetricType::Counter,
+                _ => MetricType::Gauge,
+            },
+        };
+        readings.push(ExternalReading {
+            value,
+            kind: name.to_string(),
+            unit: String::new(),
+            metric_type,
+            labels,
+            timestamp,
+            help: help.get(name).cloned(),
+        });
+    }
+    Ok(readings)
+}
+
+/// Parses `a="1",b="x\"y"}` and returns the labels and the length up to and including `}`.
+fn parse_prometheus_labels(input: &str) -> Result<(BTreeMap<String, String>, usize)> {
+    let mut labels = BTreeMap::new();
+    let mut chars = input.char_indices().peekable();
+    loop {
+        while let Some((_, c)) = chars.peek() {
+            if *c == ',' || c.is_whitespace() {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let mut key = String::new();
+        loop {
+            match chars.next() {
+                Some((i, '}')) if key.is_empty() => return Ok((labels, i + 1)),
+                Some((_, '=')) => break,
+                Some((_, c)) => key.push(c),
+                None => bail!("Unterminated label set"),
+            }
+        }
+        if !matches!(chars.next(), Some((_, '"'))) {
+            bail!("Label '{}' has no quoted value", key);
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next() {
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c)) => value.push(c),
+                    None => bail!("Unterminated label value"),
+                },
+                Some((_, '"')) => break,
+                Some((_, c)) => value.push(c),
+                None => bail!("Unterminated label value"),
+            }
+        }
+        labels.insert(key.trim().to_string(), value);
+    }
+}
+
+/// InfluxDB line protocol: `measurement,tag=a field=1.0,other=2i 1700000000000000000`.
+/// Each numeric or boolean field becomes a reading of kind `<measurement>_<field>`
+/// (just `<measurement>` for a field called `value`), tags become labels.
+pub fn parse_influx(output: &str) -> Result<Vec<ExternalReading>> {
+    let mut readings = vec![];
+    for line in output.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parts = split_unescaped(line, ' ');
+        if parts.len() < 2 {
+            bail!("Invalid line: '{}'", line);
+        }
+        let mut series = split_unescaped(&parts[0], ',').into_iter();
+        let measurement = unescape(&series.next().unwrap_or_default());
+        let mut labels = BTreeMap::new();
+        for tag in series {
+            let (k, v) = split_pair(&tag)?;
+            labels.insert(k, v);
+        }
+        // nanoseconds
+        let timestamp = parts
+            .get(2)
+            .map(|t| t.parse::<i64>().map(|ns| ns / 1_000_000))
+            .transpose()?;
+
+        for field in split_unescaped(&parts[1], ',') {
+            let (key, raw) = split_pair(&field)?;
+            let value = match raw.as_str() {
+                "t" | "T" | "true" | "True" | "TRUE" => 1.0,
+                "f" | "F" | "false" | "False" | "FALSE" => 0.0,
+                s if s.starts_with('"') => continue,
+                s => s
+                    .trim_end_matches(|c| c == 'i' || c == 'u')
+                    .parse::<f64>()?,
+            };
+            let kind = if key == "value" {
+                measurement.clone()
+            } else {
+                format!("{}_{}", measurement, key)
+            };
+            readings.push(ExternalReading {
+                value,
+                kind,
+                unit: String::new(),
+                labels: labels.clone(),
+                timestamp,
+                ..Default::default()
+            });
+        }
+    }
+    Ok(readings)
+}
+
+/// Splits at `sep` unless it's escaped with a backslash or inside double quotes.
+fn split_unescaped(input: &str, sep: char) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut escaped = false;
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            _ if escaped => {
+                current.push(c);
+                escaped = false;
+            }
+            '\\' => {
+                current.push(c);
+                escaped = true;
+            }
+            '"' => {
+                current.push(c);
+                quoted = !quoted;
+            }
+            _ if c == sep && !quoted => parts.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    parts.push(current);
+    parts
+}
+
+fn split_pair(pair: &str) -> Result<(String, String)> {
+    let parts = split_unescaped(pair, '=');
+    match parts.as_slice() {
+        [k, v] => Ok((unescape(k), unescape(v))),
+        _ => bail!("Invalid key/value pair: '{}'", pair),
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(next) = chars.next() {
+                out.push(next);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_parse_prometheus_reads_types_labels_and_help() {
+        let text = r#"
+# HELP power_watts Current power draw
+# TYPE power_watts gauge
+power_watts{plug="desk",room="a \"b\""} 42.5
+# TYPE energy_total counter
+energy_total{plug="desk"} 1337 1700000000000
+up 1
+"#;
+        let readings = parse_prometheus(text).unwrap();
+        assert_eq!(readings.len(), 3);
+        assert_eq!(readings[0].kind, "power_watts");
+        assert_eq!(readings[0].value, 42.5);
+        assert_eq!(readings[0].labels["room"], "a \"b\"");
+        assert_eq!(readings[0].help.as_deref(), Some("Current power draw"));
+        assert_eq!(readings[1].metric_type, MetricType::Counter);
+        assert_eq!(readings[1].timestamp, Some(1_700_000_000_000));
+        assert_eq!(readings[2].kind, "up");
+        assert!(readings[2].labels.is_empty());
+    }
+
+    #[test]
+    fn test_parse_influx_splits_fields_and_tags() {
+        let text = "weather,location=us\\ west temperature=82.5,humidity=43i,raining=t,note=\"a, b\" 1700000000000000000\ncpu value=0.5";
+        let readings = parse_influx(text).unwrap();
+        assert_eq!(readings.len(), 4);
+        assert_eq!(readings[0].kind, "weather_temperature");
+        assert_eq!(readings[0].labels["location"], "us west");
+        assert_eq!(readings[0].timestamp, Some(1_700_000_000_000));
+        assert_eq!(readings[1].value, 43.0);
+        assert_eq!(readings[2].value, 1.0);
+        assert_eq!(readings[3].kind, "cpu");
+        assert_eq!(readings[3].timestamp, None);
+    }
+
+    #[test]
+    fn test_Parser_from_config_defaults_to_json() {
+        let mut config = ExternalConfig::with_path("/bin/true");
+        assert_eq!(Parser::from_config(&config), Parser::Json);
+        config.format = OutputFormat::Plain;
+        config.unit = Some("ppm".into());
+        assert_eq!(
+            Parser::from_config(&config),
+            Parser::Plain {
+                kind: "value".into(),
+                unit: "ppm".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_plain_uses_configured_kind() {
+        let readings = parse_plain(" 415\n", "co2", "ppm").unwrap();
+        assert_eq!(readings[0].value, 415.0);
+        assert_eq!(readings[0].kind, "co2");
+        assert_eq!(readings[0].unit, "ppm");
+        assert!(parse_plain("n/a", "co2", "ppm").is_err());
+    }
+}
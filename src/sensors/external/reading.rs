@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How a reading's value is applied to its collector.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricType {
+    /// The value is set as is
+    #[default]
+    Gauge,
+    /// The value is a running total kept by the source, resets are detected
+    Counter,
+    /// The value is added to a counter
+    Increment,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct ExternalReading {
+    pub value: f64,
+    pub kind: String,
+    pub unit: String,
+    #[serde(default, rename = "type")]
+    pub metric_type: MetricType,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Milliseconds since the epoch when the source took the reading
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+}
+
+impl ExternalReading {
+    /// Plain gauges without extra labels go into the sensor's main gauge.
+    pub fn is_simple(&self) -> bool {
+        self.metric_type == MetricType::Gauge && self.labels.is_empty()
+    }
+}
+
+/// Accepts a JSON array of readings or a single reading object.
+pub fn parse_json(output: &str) -> serde_json::Result<Vec<ExternalReading>> {
+    serde_json::from_str::<Vec<ExternalReading>>(output)
+        .or_else(|_| serde_json::from_str::<ExternalReading>(output).map(|r| vec![r]))
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_parse_json_accepts_minimal_readings() {
+        let readings =
+            parse_json(r#"[{"value": 21.5, "kind": "temperature", "unit": "celsius"}]"#).unwrap();
+        assert_eq!(
+            readings,
+            vec![ExternalReading {
+                value: 21.5,
+                kind: "temperature".into(),
+                unit: "celsius".into(),
+                ..Default::default()
+            }]
+        );
+        assert!(readings[0].is_simple());
+    }
+
+    #[test]
+    fn test_parse_json_reads_extended_schema() {
+        let readings = parse_json(
+            r#"{"value": 3, "kind": "requests", "unit": "count", "type": "increment",
+                "labels": {"port": "2"}, "timestamp": 1700000000000, "help": "Requests served"}"#,
+        )
+        .unwrap();
+        assert_eq!(readings[0].metric_type, MetricType::Increment);
+        assert_eq!(readings[0].labels["port"], "2");
+        assert_eq!(readings[0].timestamp, Some(1_700_000_000_000));
+        assert_eq!(readings[0].help.as_deref(), Some("Requests served"));
+        assert!(!readings[0].is_simple());
+    }
+}
@@ -3,19 +3,19 @@ mod state;
 mod util;
 
 use crate::{
-    config::Config,
+    config::{file::Labels, Config},
     msg::{DeviceControl, Value},
     sensors::mqtt_heater::{
         requests::HeaterFanStateUpdateRequest,
         state::{operation_state, to_topic},
     },
+    utils::{label_names, label_values, mqtt},
 };
 use anyhow::{bail, Result};
 use async_std::{
     sync::RwLock,
     task::{self, JoinHandle},
 };
-use core::time::Duration;
 use futures_util::future::{self};
 use log::{debug, error, info};
 use rumqttc::{AsyncClient, ClientError, Event, MqttOptions, Packet, Publish, QoS};
@@ -25,7 +25,7 @@ use url::Url;
 use uuid::Uuid;
 use xactor::*;
 
-use crate::msg::{ReadNow, SensorReading, SetupMetrics};
+use crate::msg::{MetricSpec, ReadNow, SensorReading, SetupMetrics};
 
 use self::{
     requests::{update_webhook_state, ThermostatState},
@@ -51,10 +51,17 @@ pub struct MqttHeaterReader {
     name: String,
     connection: Option<MqttConnection>,
     state: Arc<RwLock<HashMap<String, HeaterFanState>>>,
+    labels: Labels,
 }
 
 impl MqttHeaterReader {
-    pub fn new<I: Into<String>>(address: Url, webhook_url: Url, name: I, device_id: I) -> Self {
+    pub fn new<I: Into<String>>(
+        address: Url,
+        webhook_url: Url,
+        name: I,
+        device_id: I,
+        labels: Labels,
+    ) -> Self {
         MqttHeaterReader {
             address,
             webhook: webhook_url,
@@ -64,8 +71,13 @@ impl MqttHeaterReader {
             connection: None,
             device_id: device_id.into(),
             state: Arc::new(RwLock::new(HashMap::new())),
+            labels,
         }
     }
+
+    fn reading_labels(&self, kind: &str, unit: &str) -> Vec<String> {
+        label_values(vec![kind.to_string(), unit.to_string()], &self.labels)
+    }
 }
 
 #[async_trait::async_trait]
@@ -75,13 +87,12 @@ impl Actor for MqttHeaterReader {
             panic!("Unexpected restart");
         }
 
-        let mut options = MqttOptions::new(
+        let options = mqtt::options(
             self.collector_id.as_hyphenated().to_string(),
-            self.address.host_str().unwrap().to_string(),
-            self.address.port().unwrap_or(1883),
-        );
-
-        options.set_keep_alive(Duration::from_secs(5));
+            &self.address,
+            None,
+            None,
+        )?;
 
         let (client, mut eventloop) = AsyncClient::new(options.clone(), 1000);
         info!("MQTT Connection established: {:?}", self);
@@ -140,9 +151,12 @@ impl Actor for MqttHeaterReader {
         });
         let mut addr = Broker::from_registry().await?;
         addr.publish(SetupMetrics::Gauge(
-            self.collector_id,
-            self.name.clone(),
-            vec![String::from("kind"), String::from("unit")],
+            MetricSpec::new(
+                self.collector_id,
+                self.name.clone(),
+                label_names(&["kind", "unit"], &self.labels),
+            )
+            .help("Heater fan readings received via MQTT"),
         ))?;
 
         info!("MQTT listener up");
@@ -181,7 +195,8 @@ impl Handler<ReadNow> for MqttHeaterReader {
                     Some(SensorReading {
                         id: self.collector_id,
                         reading: Value::Simple(if *s { 1.0 } else { 0.0 }),
-                        labels: vec![String::from("power_on"), String::from("onoff")],
+                        labels: self.reading_labels("power_on", "onoff"),
+                        timestamp: None,
                     })
                 }
                 HeaterFanState::CurrentTemperature(s) => {
@@ -189,7 +204,8 @@ impl Handler<ReadNow> for MqttHeaterReader {
                     Some(SensorReading {
                         id: self.collector_id,
                         reading: Value::Simple(*s as f32),
-                        labels: vec![String::from("local_temperature"), String::from("celsius")],
+                        labels: self.reading_labels("local_temperature", "celsius"),
+                        timestamp: None,
                     })
                 }
 
@@ -198,7 +214,8 @@ impl Handler<ReadNow> for MqttHeaterReader {
                     Some(SensorReading {
                         id: self.collector_id,
                         reading: Value::Simple(*s as f32),
-                        labels: vec![String::from("fan_speed"), String::from("steps")],
+                        labels: self.reading_labels("fan_speed", "steps"),
+                        timestamp: None,
                     })
                 }
                 HeaterFanState::Oscillate(s) => {
@@ -206,7 +223,8 @@ impl Handler<ReadNow> for MqttHeaterReader {
                     Some(SensorReading {
                         id: self.collector_id,
                         reading: Value::Simple(if *s { 1.0 } else { 0.0 }),
-                        labels: vec![String::from("oscillate"), String::from("onoff")],
+                        labels: self.reading_labels("oscillate", "onoff"),
+                        timestamp: None,
                     })
                 }
                 HeaterFanState::TargetTemperature(s) => {
@@ -331,11 +349,13 @@ impl Handler<DeviceControl> for MqttHeaterReader {
 }
 
 pub async fn setup(config: &Config) -> Result<Addr<MqttHeaterReader>> {
+    let instance = config.file.mqtt_heater.clone().unwrap_or_default();
     MqttHeaterReader::new(
         config.mqtt_address()?,
         config.webhook_url()?,
-        &config.metrics_name,
-        &config.heaterfan_mac()?,
+        instance.name.unwrap_or_else(|| config.metrics_name.clone()),
+        config.heaterfan_mac()?,
+        instance.labels,
     )
     .start()
     .await
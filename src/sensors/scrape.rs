@@ -0,0 +1,310 @@
+use crate::{
+    config::{
+        file::{InstanceConfig, ScrapeConfig},
+        Config,
+    },
+    msg::{ReadNow, SensorReading, Value},
+    sensors::external::{
+        collectors::{Collectors, SourceMetrics},
+        parsers::parse_prometheus,
+        reading::{ExternalReading, MetricType},
+    },
+    utils::label_names,
+};
+use anyhow::{anyhow, bail};
+use async_std::future::timeout;
+use core::time::Duration;
+use log::{debug, error, info, warn};
+use std::time::Instant;
+use url::Url;
+use xactor::*;
+
+/// Matches `pattern` against `name`, where `*` stands for any number of characters.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            name.starts_with(prefix)
+                && (0..=name.len() - prefix.len())
+                    .filter(|i| name.is_char_boundary(prefix.len() + i))
+                    .any(|i| glob_match(rest, &name[prefix.len() + i..]))
+        }
+    }
+}
+
+/// Applies the include/exclude filters and label rewrites of a scrape target.
+fn relabel(config: &ScrapeConfig, mut reading: ExternalReading) -> Option<ExternalReading> {
+    let kept =
+        config.include.is_empty() || config.include.iter().any(|p| glob_match(p, &reading.kind));
+    if !kept || config.exclude.iter().any(|p| glob_match(p, &reading.kind)) {
+        return None;
+    }
+    reading.labels = reading
+        .labels
+        .into_iter()
+        .filter(|(k, _)| !config.drop_labels.contains(k))
+        .map(|(k, v)| match config.rename_labels.get(&k) {
+            Some(renamed) => (renamed.clone(), v),
+            None => (k, v),
+        })
+        // static labels take precedence
+        .filter(|(k, _)| !config.labels.contains_key(k))
+        .collect();
+    Some(reading)
+}
+
+pub struct PrometheusScraper {
+    config: ScrapeConfig,
+    name: String,
+    resolution: Duration,
+    timeout: Duration,
+    metrics: SourceMetrics,
+    collectors: Collectors,
+}
+
+impl PrometheusScraper {
+    pub fn new<I: Into<String>>(
+        config: ScrapeConfig,
+        name: I,
+        resolution: Duration,
+        metrics: SourceMetrics,
+    ) -> Self {
+        let name = name.into();
+        PrometheusScraper {
+            collectors: Collectors::new(
+                name.clone(),
+                config.url.clone(),
+                label_names(&[], &config.labels),
+                Some(resolution),
+            ),
+            timeout: config
+                .timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(resolution),
+            config,
+            name,
+            resolution,
+            metrics,
+        }
+    }
+
+    async fn fetch(&self) -> anyhow::Result<String> {
+        debug!("Scraping {}", self.config.url);
+        let request = async {
+            let mut response = surf::get(&self.config.url)
+                .header("accept", "text/plain")
+                .await
+                .map_err(|e| e.into_inner())?;
+            if !response.status().is_success() {
+                bail!("returned {}", response.status());
+            }
+            response.body_string().await.map_err(|e| e.into_inner())
+        };
+        timeout(self.timeout, request)
+            .await
+            .map_err(|_| anyhow!("timed out after {:?}", self.timeout))?
+    }
+
+    fn to_reading(&mut self, v: ExternalReading) -> SensorReading {
+        let counter = v.metric_type == MetricType::Counter;
+        let id = self.collectors.collector_for(
+            counter,
+            &v.kind,
+            v.help.as_ref(),
+            v.labels.keys().cloned().collect(),
+        );
+        let labels: Vec<String> = self
+            .config
+            .labels
+            .values()
+            .chain(v.labels.values())
+            .cloned()
+            .collect();
+
+        let reading = if counter {
+            Value::IncBy(self.collectors.increase(id, &labels, v.value) as f32)
+        } else {
+            Value::Simple(v.value as f32)
+        };
+        SensorReading {
+            id,
+            reading,
+            labels,
+            timestamp: v.timestamp,
+        }
+    }
+
+    fn error_reading(&self, e: &anyhow::Error) -> SensorReading {
+        error!("Scraping '{}' failed: {}", self.config.url, e);
+        self.metrics
+            .error(vec![self.name.clone(), self.config.url.clone()])
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for PrometheusScraper {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.send_interval(ReadNow, self.resolution);
+        info!("Scraping '{}' as '{}'", self.config.url, self.name);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<ReadNow> for PrometheusScraper {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: ReadNow) {
+        let started = Instant::now();
+        let result = self.fetch().await.and_then(|body| parse_prometheus(&body));
+        let mut readings = vec![self.metrics.duration(
+            vec![self.name.clone(), self.config.url.clone()],
+            started.elapsed(),
+        )];
+
+        match result {
+            Ok(series) => {
+                let series: Vec<_> = series
+                    .into_iter()
+                    .filter_map(|v| relabel(&self.config, v))
+                    .collect();
+                if series.is_empty() {
+                    warn!("No series left from '{}'", self.config.url);
+                }
+                readings.extend(series.into_iter().map(|v| self.to_reading(v)));
+            }
+            Err(e) => readings.push(self.error_reading(&e)),
+        }
+
+        self.collectors.publish(readings).await;
+    }
+}
+
+/// Unnamed targets get the host and port appended to the global name
+/// when there is more than one, e.g. `roomA_plug1_9100`.
+fn default_name(metrics_name: &str, target: &ScrapeConfig, multiple: bool) -> String {
+    if multiple {
+        let source = match Url::parse(&target.url) {
+            Ok(url) => format!(
+                "{}_{}",
+                url.host_str().unwrap_or_default(),
+                url.port_or_known_default().unwrap_or_default()
+            ),
+            Err(_) => target.url.clone(),
+        };
+        let source: String = source
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        target.name_or(&format!("{}_{}", metrics_name, source))
+    } else {
+        target.name_or(metrics_name)
+    }
+}
+
+pub async fn setup(config: &Config) -> Result<Vec<Addr<PrometheusScraper>>> {
+    let targets = &config.file.scrape;
+    if targets.is_empty() {
+        return Ok(vec![]);
+    }
+    info!("Scrape module active, {} targets found", targets.len());
+
+    let metrics = SourceMetrics::setup(
+        "scrape",
+        &["name", "url"],
+        "Failed scrapes of remote Prometheus endpoints",
+    )
+    .await?;
+    metrics
+        .setup_duration(
+            "scrape",
+            &["name", "url"],
+            "How long a scrape of a remote Prometheus endpoint takes",
+            &config.file.scrape_duration,
+        )
+        .await?;
+
+    let multiple = targets.len() > 1;
+    let mut scrapers = vec![];
+    for target in targets.iter().cloned() {
+        let name = default_name(&config.metrics_name, &target, multiple);
+        let resolution = target.resolution_or(config.resolution());
+        scrapers.push(
+            PrometheusScraper::new(target, name, resolution, metrics)
+                .start()
+                .await?,
+        );
+    }
+    Ok(scrapers)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use crate::config::file::Labels;
+
+    fn target() -> ScrapeConfig {
+        ScrapeConfig {
+            url: "http://localhost/metrics".into(),
+            name: None,
+            resolution_ms: None,
+            timeout_ms: None,
+            labels: Labels::from([("room".to_string(), "A".to_string())]),
+            include: vec!["power_*".into(), "energy_total".into()],
+            exclude: vec!["*_created".into()],
+            rename_labels: [("instance".to_string(), "device".to_string())].into(),
+            drop_labels: vec!["job".into()],
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("power_*", "power_watts"));
+        assert!(glob_match("*_total", "energy_total"));
+        assert!(glob_match("a*c*e", "abcde"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("power_*", "energy_total"));
+        assert!(!glob_match("power", "power_watts"));
+    }
+
+    #[test]
+    fn test_default_name_tells_unnamed_targets_apart() {
+        let plug = ScrapeConfig {
+            url: "http://plug-1.local:9100/metrics".into(),
+            ..target()
+        };
+        let named = ScrapeConfig {
+            name: Some("solar".into()),
+            ..target()
+        };
+        assert_eq!(default_name("roomA", &plug, false), "roomA");
+        assert_eq!(
+            default_name("roomA", &plug, true),
+            "roomA_plug_1_local_9100"
+        );
+        assert_eq!(default_name("roomA", &target(), true), "roomA_localhost_80");
+        assert_eq!(default_name("roomA", &named, true), "solar");
+    }
+
+    #[test]
+    fn test_relabel_filters_and_rewrites() {
+        let series = parse_prometheus(
+            r#"power_watts{instance="plug1",job="tasmota",room="B"} 42
+power_created 1700000000
+energy_total 3
+voltage 230"#,
+        )
+        .unwrap();
+        let kept: Vec<_> = series
+            .into_iter()
+            .filter_map(|v| relabel(&target(), v))
+            .collect();
+
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].kind, "power_watts");
+        assert_eq!(
+            kept[0].labels,
+            [("device".to_string(), "plug1".to_string())].into()
+        );
+        assert_eq!(kept[1].kind, "energy_total");
+    }
+}
@@ -0,0 +1,11 @@
+#[cfg(feature = "sink-influx")]
+pub mod influx;
+
+#[cfg(feature = "sink-mqtt")]
+pub mod mqtt;
+
+#[cfg(feature = "sink-pushgateway")]
+pub mod pushgateway;
+
+#[cfg(feature = "sink-remote-write")]
+pub mod remote_write;
@@ -0,0 +1,339 @@
+use crate::{
+    config::file::InfluxConfig,
+    config::Config,
+    db::sanitize_name,
+    msg::{MetricSpec, SensorReading, SetupMetrics, Value},
+};
+use anyhow::anyhow;
+use async_std::future::timeout;
+use core::time::Duration;
+use log::{debug, error, info, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+use uuid::Uuid;
+use xactor::*;
+
+#[message]
+#[derive(Clone, Debug)]
+struct Flush;
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The sanitized `<subsystem>_<name>`, like the Prometheus name without its namespace.
+pub fn measurement(spec: &MetricSpec) -> String {
+    match &spec.subsystem {
+        Some(subsystem) => sanitize_name(&format!("{}_{}", subsystem, spec.name_with_unit())),
+        None => sanitize_name(&spec.name_with_unit()),
+    }
+}
+
+/// One line of line protocol: `<measurement>,<tags> value=<value> <timestamp ms>`.
+/// Tags with empty values are left out, Influx doesn't accept them.
+pub fn to_line(measurement: &str, tags: &[(&str, &str)], value: f64, timestamp_ms: i64) -> String {
+    let mut line = escape(measurement, &[',', ' ']);
+    for (k, v) in tags.iter().filter(|(_, v)| !v.is_empty()) {
+        line.push(',');
+        line.push_str(&escape(k, &[',', '=', ' ']));
+        line.push('=');
+        line.push_str(&escape(v, &[',', '=', ' ']));
+    }
+    line.push_str(&format!(" value={} {}", value, timestamp_ms));
+    line
+}
+
+enum WriteError {
+    /// Worth trying again later
+    Retry(anyhow::Error),
+    /// Influx rejected the lines, it won't take them later either
+    Rejected(anyhow::Error),
+}
+
+/// Writes every reading to InfluxDB v2, batched every flush interval.
+/// Counters are written as their running total.
+pub(crate) struct InfluxSink {
+    url: Url,
+    token: Option<String>,
+    interval: Duration,
+    timeout: Duration,
+    max_pending: usize,
+    collectors: HashMap<Uuid, (String, Vec<String>)>,
+    totals: HashMap<(Uuid, Vec<String>), f64>,
+    pending: VecDeque<String>,
+}
+
+impl InfluxSink {
+    pub fn new(config: &InfluxConfig, interval: Duration) -> anyhow::Result<Self> {
+        // appended, joining would replace the last segment of e.g. `http://host/influx`
+        let mut url = Url::parse(&config.url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("'{}' can't have a path", config.url))?
+            .pop_if_empty()
+            .extend(&["api", "v2", "write"]);
+        url.query_pairs_mut()
+            .append_pair("org", &config.org)
+            .append_pair("bucket", &config.bucket)
+            .append_pair("precision", "ms");
+        Ok(InfluxSink {
+            url,
+            token: config.token.clone(),
+            interval,
+            timeout: config
+                .timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(interval),
+            max_pending: config.max_pending_lines.max(1),
+            collectors: HashMap::new(),
+            totals: HashMap::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn record(&mut self, msg: SensorReading, timestamp_ms: i64) {
+        let (name, label_names) = match self.collectors.get(&msg.id) {
+            Some(c) => c,
+            None => return,
+        };
+        let value = match msg.reading {
+            Value::Simple(v) => v as f64,
+            Value::Observe(v) => v,
+            delta => {
+                let total = self.totals.entry((msg.id, msg.labels.clone())).or_default();
+                *total += match delta {
+                    Value::Inc => 1.0,
+                    Value::IncBy(v) => v as f64,
+                    Value::Dec => -1.0,
+                    _ => 0.0,
+                };
+                *total
+            }
+        };
+        if !value.is_finite() {
+            // line protocol has no NaN or infinity, Influx would reject the whole batch
+            debug!("Skipping non-finite value of '{}'", name);
+            return;
+        }
+        let tags: Vec<(&str, &str)> = label_names
+            .iter()
+            .map(String::as_str)
+            .zip(msg.labels.iter().map(String::as_str))
+            .collect();
+        self.pending
+            .push_back(to_line(name, &tags, value, timestamp_ms));
+        while self.pending.len() > self.max_pending {
+            self.pending.pop_front();
+        }
+    }
+
+    async fn write(&self, body: String) -> std::result::Result<(), WriteError> {
+        let mut request = surf::post(self.url.as_str())
+            .body(body)
+            .header("Content-Type", "text/plain; charset=utf-8");
+        if let Some(token) = &self.token {
+            request = request.header("Authorization", format!("Token {}", token));
+        }
+        let response = timeout(self.timeout, request)
+            .await
+            .map_err(|_| WriteError::Retry(anyhow!("timed out after {:?}", self.timeout)))?
+            .map_err(|e| WriteError::Retry(e.into_inner()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status == surf::StatusCode::TooManyRequests {
+            Err(WriteError::Retry(anyhow!("InfluxDB returned {}", status)))
+        } else {
+            Err(WriteError::Rejected(anyhow!(
+                "InfluxDB returned {}",
+                status
+            )))
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for InfluxSink {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.subscribe::<SetupMetrics>().await?;
+        ctx.subscribe::<SensorReading>().await?;
+        ctx.send_interval(Flush, self.interval);
+        info!(
+            "Writing readings to '{}' every {:?}",
+            self.url, self.interval
+        );
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<SetupMetrics> for InfluxSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SetupMetrics) {
+        let spec = match msg {
+            SetupMetrics::Gauge(spec)
+            | SetupMetrics::Counter(spec)
+            | SetupMetrics::Histogram(spec, _)
+            | SetupMetrics::Summary(spec, _) => spec,
+        };
+        self.collectors
+            .insert(spec.id, (measurement(&spec), spec.labels));
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<SensorReading> for InfluxSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SensorReading) {
+        let timestamp = msg.timestamp.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or_default()
+        });
+        self.record(msg, timestamp);
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<Flush> for InfluxSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Flush) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let lines = self.pending.len();
+        let body = self.pending.iter().cloned().collect::<Vec<_>>().join("\n");
+        match self.write(body).await {
+            Ok(()) => self.pending.clear(),
+            Err(WriteError::Rejected(e)) => {
+                error!("Dropping {} lines for '{}': {}", lines, self.url, e);
+                self.pending.clear();
+            }
+            Err(WriteError::Retry(e)) => warn!(
+                "Couldn't write to '{}', {} lines pending: {}",
+                self.url, lines, e
+            ),
+        }
+    }
+}
+
+pub async fn setup(config: &Config) -> Result<Option<Addr<InfluxSink>>> {
+    match &config.file.influx {
+        Some(influx) => {
+            let interval = influx
+                .flush_interval_ms
+                .map(Duration::from_millis)
+                .unwrap_or_else(|| config.resolution());
+            let sink = InfluxSink::new(influx, interval)?;
+            Ok(Some(sink.start().await?))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_to_line_escapes_and_skips_empty_tags() {
+        assert_eq!(
+            to_line(
+                "room A",
+                &[
+                    ("kind", "temperature"),
+                    ("unit", ""),
+                    ("place", "desk,left")
+                ],
+                21.5,
+                1_700_000_000_000
+            ),
+            r"room\ A,kind=temperature,place=desk\,left value=21.5 1700000000000"
+        );
+    }
+
+    fn config() -> InfluxConfig {
+        InfluxConfig {
+            url: "http://localhost:8086".into(),
+            org: "home".into(),
+            bucket: "sensors".into(),
+            token: None,
+            flush_interval_ms: None,
+            timeout_ms: None,
+            max_pending_lines: 2,
+        }
+    }
+
+    fn reading(id: Uuid, reading: Value) -> SensorReading {
+        SensorReading {
+            id,
+            reading,
+            labels: vec!["read".into()],
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_InfluxSink_appends_write_path() {
+        let sink = InfluxSink::new(&config(), Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            sink.url.as_str(),
+            "http://localhost:8086/api/v2/write?org=home&bucket=sensors&precision=ms"
+        );
+        let behind_proxy = InfluxSink::new(
+            &InfluxConfig {
+                url: "http://localhost/influx".into(),
+                ..config()
+            },
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        assert_eq!(behind_proxy.url.path(), "/influx/api/v2/write");
+    }
+
+    #[test]
+    fn test_measurement_is_sanitized_with_subsystem() {
+        assert_eq!(
+            measurement(&MetricSpec::new(Uuid::new_v4(), "room A,1", vec![]).subsystem("external")),
+            "external_room_A_1"
+        );
+    }
+
+    #[test]
+    fn test_InfluxSink_writes_counters_as_totals() {
+        let id = Uuid::new_v4();
+        let mut sink = InfluxSink::new(&config(), Duration::from_secs(1)).unwrap();
+        sink.collectors
+            .insert(id, ("errors_total".into(), vec!["stage".into()]));
+        for at in 1..=3 {
+            sink.record(reading(id, Value::Inc), at);
+        }
+        assert_eq!(
+            sink.pending,
+            vec![
+                "errors_total,stage=read value=2 2",
+                "errors_total,stage=read value=3 3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_InfluxSink_skips_non_finite_values() {
+        let id = Uuid::new_v4();
+        let mut sink = InfluxSink::new(&config(), Duration::from_secs(1)).unwrap();
+        sink.collectors
+            .insert(id, ("co2".into(), vec!["stage".into()]));
+        sink.record(reading(id, Value::Simple(f32::NAN)), 1);
+        sink.record(reading(id, Value::Observe(f64::INFINITY)), 2);
+        sink.record(reading(id, Value::Simple(400.0)), 3);
+        assert_eq!(sink.pending, vec!["co2,stage=read value=400 3"]);
+    }
+}
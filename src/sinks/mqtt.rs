@@ -0,0 +1,348 @@
+mod homeassistant;
+
+#[cfg(feature = "switch-mqtt")]
+use crate::msg::SetupSwitch;
+use crate::{
+    config::file::MqttSinkConfig,
+    config::Config,
+    msg::{SensorReading, SetupMetrics, Value},
+    utils::mqtt::{self, qos, topic_level},
+};
+use async_std::task::{self, JoinHandle};
+use core::time::Duration;
+use log::{debug, error, info};
+use rumqttc::{AsyncClient, QoS};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+use uuid::Uuid;
+use xactor::*;
+
+/// `<prefix>/<name>/<kind>/<other label values>`, so every series has a topic
+/// of its own. The unit goes with the kind and is only part of the payload.
+pub fn topic(prefix: &str, name: &str, label_names: &[String], labels: &[String]) -> String {
+    let mut levels = vec![prefix.trim_end_matches('/').to_string(), topic_level(name)];
+    let position = |wanted: &str| label_names.iter().position(|n| n == wanted);
+    let (kind, unit) = (position("kind"), position("unit"));
+    levels.extend(kind.and_then(|i| labels.get(i)).map(|k| topic_level(k)));
+    levels.extend(
+        labels
+            .iter()
+            .enumerate()
+            .filter(|(i, v)| Some(*i) != kind && Some(*i) != unit && !v.is_empty())
+            .map(|(_, v)| topic_level(v)),
+    );
+    levels.join("/")
+}
+
+/// The JSON payload of a reading: `{"value", "unit", "labels", "timestamp"}`,
+/// the timestamp is in milliseconds since the epoch.
+pub fn payload(label_names: &[String], labels: &[String], value: f64, timestamp_ms: i64) -> String {
+    let labels: BTreeMap<&str, &str> = label_names
+        .iter()
+        .map(String::as_str)
+        .zip(labels.iter().map(String::as_str))
+        .collect();
+    json!({
+        "value": value,
+        "unit": labels.get("unit"),
+        "labels": labels,
+        "timestamp": timestamp_ms,
+    })
+    .to_string()
+}
+
+/// Publishes every reading to an MQTT broker. Counters are published as their
+/// running total.
+///
+/// With Home Assistant discovery enabled, each gauge series is announced once,
+/// as are switches if `switch-mqtt` handles their commands.
+pub(crate) struct MqttSink {
+    address: Url,
+    node: String,
+    config: MqttSinkConfig,
+    qos: QoS,
+    collectors: HashMap<Uuid, (String, Vec<String>)>,
+    gauges: HashSet<Uuid>,
+    announced: HashSet<(Uuid, Vec<String>)>,
+    totals: HashMap<(Uuid, Vec<String>), f64>,
+    client: Option<AsyncClient>,
+    eventloop_task: Option<JoinHandle<()>>,
+}
+
+impl MqttSink {
+    pub fn new(address: Url, node: String, mut config: MqttSinkConfig) -> anyhow::Result<Self> {
+        config.prefix = config.prefix.trim_end_matches('/').to_string();
+        Ok(MqttSink {
+            address,
+            node,
+            qos: qos(config.qos)?,
+            config,
+            collectors: HashMap::new(),
+            gauges: HashSet::new(),
+            announced: HashSet::new(),
+            totals: HashMap::new(),
+            client: None,
+            eventloop_task: None,
+        })
+    }
+
+    /// The discovery message for a gauge series that hasn't been announced yet.
+    fn discovery(&mut self, msg: &SensorReading) -> Option<(String, String)> {
+        if !self.config.homeassistant || !self.gauges.contains(&msg.id) {
+            return None;
+        }
+        let (name, label_names) = self.collectors.get(&msg.id)?;
+        if !self.announced.insert((msg.id, msg.labels.clone())) {
+            return None;
+        }
+        let label = |wanted: &str| {
+            label_names
+                .iter()
+                .position(|n| n == wanted)
+                .and_then(|i| msg.labels.get(i))
+                .map(String::as_str)
+        };
+        let topic = topic(&self.config.prefix, name, label_names, &msg.labels);
+        Some(homeassistant::sensor_config(
+            &self.config.discovery_prefix,
+            &self.config.prefix,
+            &self.node,
+            &topic[self.config.prefix.len() + 1..],
+            label("kind"),
+            label("unit"),
+        ))
+    }
+
+    async fn publish(&self, topic: &str, payload: String) {
+        if let Some(client) = &self.client {
+            debug!("Publishing to '{}': {}", topic, payload);
+            if let Err(e) = client
+                .publish(topic, self.qos, self.config.retain, payload)
+                .await
+            {
+                error!("Couldn't publish to '{}': {}", topic, e);
+            }
+        }
+    }
+
+    /// Topic and payload for a reading, `None` for unknown collectors and observations.
+    fn message(&mut self, msg: SensorReading, timestamp_ms: i64) -> Option<(String, String)> {
+        let (name, label_names) = self.collectors.get(&msg.id)?;
+        let value = match msg.reading {
+            Value::Simple(v) => v as f64,
+            Value::Observe(_) => return None,
+            delta => {
+                let total = self.totals.entry((msg.id, msg.labels.clone())).or_default();
+                *total += match delta {
+                    Value::Inc => 1.0,
+                    Value::IncBy(v) => v as f64,
+                    Value::Dec => -1.0,
+                    _ => 0.0,
+                };
+                *total
+            }
+        };
+        Some((
+            topic(&self.config.prefix, name, label_names, &msg.labels),
+            payload(label_names, &msg.labels, value, timestamp_ms),
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for MqttSink {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        let options = mqtt::options(
+            format!("jotunheim-{}", Uuid::new_v4().as_simple()),
+            &self.address,
+            self.config.username.as_deref(),
+            self.config.password.as_deref(),
+        )?;
+        let (client, mut eventloop) = AsyncClient::new(options, 1000);
+        let address = self.address.clone();
+        self.eventloop_task = Some(task::spawn(async move {
+            loop {
+                // polling again reconnects
+                if let Err(e) = eventloop.poll().await {
+                    error!("MQTT connection to '{}' failed: {:?}", address, e);
+                    task::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }));
+        self.client = Some(client);
+
+        // switches only take commands with `switch-mqtt`, don't announce them otherwise
+        #[cfg(feature = "switch-mqtt")]
+        ctx.subscribe::<SetupSwitch>().await?;
+        ctx.subscribe::<SetupMetrics>().await?;
+        ctx.subscribe::<SensorReading>().await?;
+        info!(
+            "Publishing readings to '{}' under '{}/'",
+            self.address, self.config.prefix
+        );
+        Ok(())
+    }
+
+    async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        if let Some(client) = self.client.take() {
+            let _ = client.disconnect().await;
+        }
+        if let Some(eventloop_task) = self.eventloop_task.take() {
+            eventloop_task.cancel().await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<SetupMetrics> for MqttSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SetupMetrics) {
+        let spec = match msg {
+            // switches are announced as switches
+            SetupMetrics::Gauge(spec) if spec.subsystem.as_deref() != Some("switch") => {
+                self.gauges.insert(spec.id);
+                spec
+            }
+            SetupMetrics::Gauge(spec) | SetupMetrics::Counter(spec) => spec,
+            SetupMetrics::Histogram(..) | SetupMetrics::Summary(..) => return,
+        };
+        self.collectors.insert(spec.id, (spec.name, spec.labels));
+    }
+}
+
+#[cfg(feature = "switch-mqtt")]
+#[async_trait::async_trait]
+impl Handler<SetupSwitch> for MqttSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SetupSwitch) {
+        if self.config.homeassistant {
+            let (topic, payload) = homeassistant::switch_config(
+                &self.config.discovery_prefix,
+                &self.config.prefix,
+                &self.node,
+                &msg.name,
+            );
+            self.publish(&topic, payload).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<SensorReading> for MqttSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SensorReading) {
+        let timestamp = msg.timestamp.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or_default()
+        });
+        if let Some((topic, payload)) = self.discovery(&msg) {
+            self.publish(&topic, payload).await;
+        }
+        if let Some((topic, payload)) = self.message(msg, timestamp) {
+            self.publish(&topic, payload).await;
+        }
+    }
+}
+
+pub async fn setup(config: &Config) -> Result<Option<Addr<MqttSink>>> {
+    match &config.file.mqtt {
+        Some(mqtt) => {
+            let address = match &mqtt.connection {
+                Some(c) => c.parse::<Url>()?,
+                None => config.mqtt_address()?,
+            };
+            let sink = MqttSink::new(address, config.metrics_name.clone(), mqtt.clone())?;
+            Ok(Some(sink.start().await?))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_topic_uses_kind_or_label_values() {
+        let names = vec!["kind".to_string(), "unit".to_string()];
+        assert_eq!(
+            topic(
+                "jotunheim/",
+                "roomA",
+                &names,
+                &["temperature".into(), "celsius".into()]
+            ),
+            "jotunheim/roomA/temperature"
+        );
+        // static labels tell series of the same kind apart
+        assert_eq!(
+            topic(
+                "jotunheim",
+                "roomA",
+                &["kind".into(), "unit".into(), "floor".into(), "room".into()],
+                &[
+                    "temperature".into(),
+                    "celsius".into(),
+                    "1".into(),
+                    "A".into()
+                ]
+            ),
+            "jotunheim/roomA/temperature/1/A"
+        );
+        assert_eq!(
+            topic(
+                "home",
+                "errors_total",
+                &["stage".into()],
+                &["read/parse #1".into()]
+            ),
+            "home/errors_total/read_parse _1"
+        );
+    }
+
+    #[test]
+    fn test_MqttSink_publishes_counters_as_totals() {
+        let mut sink = MqttSink::new(
+            "mqtt://localhost".parse().unwrap(),
+            "node".into(),
+            MqttSinkConfig::default(),
+        )
+        .unwrap();
+        let id = Uuid::new_v4();
+        sink.collectors
+            .insert(id, ("roomA".into(), vec!["kind".into(), "unit".into()]));
+        let reading = |reading| SensorReading {
+            id,
+            reading,
+            labels: vec!["switched".into(), "times".into()],
+            timestamp: None,
+        };
+        sink.message(reading(Value::IncBy(2.0)), 1);
+        let (topic, payload) = sink.message(reading(Value::Inc), 2).unwrap();
+        assert_eq!(topic, "jotunheim/roomA/switched");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&payload).unwrap(),
+            json!({
+                "value": 3.0,
+                "unit": "times",
+                "labels": {"kind": "switched", "unit": "times"},
+                "timestamp": 2,
+            })
+        );
+        assert!(sink
+            .message(
+                SensorReading {
+                    id: Uuid::new_v4(),
+                    reading: Value::Simple(1.0),
+                    labels: vec![],
+                    timestamp: None,
+                },
+                3
+            )
+            .is_none());
+    }
+}
@@ -0,0 +1,150 @@
+#[cfg(feature = "switch-mqtt")]
+use crate::utils::mqtt::switch_topic;
+use serde_json::{json, Value as JsonValue};
+
+/// Discovery topics only allow `[a-zA-Z0-9_-]` in node and object ids.
+pub fn object_id(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Home Assistant's spelling of the units our sensors report.
+fn unit_of_measurement(unit: &str) -> Option<&str> {
+    match unit {
+        "" | "none" => None,
+        "celsius" => Some("°C"),
+        "percent" => Some("%"),
+        "hpa" => Some("hPa"),
+        "ohm" => Some("Ω"),
+        other => Some(other),
+    }
+}
+
+fn device_class(kind: &str) -> Option<&str> {
+    match kind {
+        "temperature" | "local_temperature" => Some("temperature"),
+        "humidity" => Some("humidity"),
+        "pressure" | "sea_level_pressure" => Some("pressure"),
+        "co2" => Some("carbon_dioxide"),
+        _ => None,
+    }
+}
+
+fn device(node: &str) -> JsonValue {
+    json!({
+        "identifiers": [format!("jotunheim_{}", object_id(node))],
+        "name": node,
+        "manufacturer": "jotunheim",
+    })
+}
+
+/// Topic and retained payload announcing a sensor that reads its value from
+/// the JSON published on `<prefix>/<series>`, e.g. `roomA/temperature`.
+pub fn sensor_config(
+    discovery_prefix: &str,
+    prefix: &str,
+    node: &str,
+    series: &str,
+    kind: Option<&str>,
+    unit: Option<&str>,
+) -> (String, String) {
+    let id = object_id(series);
+    let state_topic = format!("{}/{}", prefix, series);
+    let mut config = json!({
+        "name": series.replace(&['/', '_'][..], " "),
+        "unique_id": format!("jotunheim_{}_{}", object_id(node), id),
+        "state_topic": state_topic,
+        "value_template": "{{ value_json.value }}",
+        "state_class": "measurement",
+        "device": device(node),
+    });
+    if let Some(unit) = unit.and_then(unit_of_measurement) {
+        config["unit_of_measurement"] = unit.into();
+    }
+    if let Some(class) = kind.and_then(device_class) {
+        config["device_class"] = class.into();
+    }
+    (
+        format!(
+            "{}/sensor/{}/{}/config",
+            discovery_prefix,
+            object_id(node),
+            id
+        ),
+        config.to_string(),
+    )
+}
+
+/// Topic and retained payload announcing a switch that's commanded and
+/// reports its state through the switch topics.
+#[cfg(feature = "switch-mqtt")]
+pub fn switch_config(
+    discovery_prefix: &str,
+    prefix: &str,
+    node: &str,
+    name: &str,
+) -> (String, String) {
+    let topic = switch_topic(prefix, node, name);
+    let config = json!({
+        "name": name,
+        "unique_id": format!("jotunheim_{}_switch_{}", object_id(node), object_id(name)),
+        "command_topic": format!("{}/set", topic),
+        "state_topic": format!("{}/state", topic),
+        "payload_on": "ON",
+        "payload_off": "OFF",
+        "device": device(node),
+    });
+    (
+        format!(
+            "{}/switch/{}/{}/config",
+            discovery_prefix,
+            object_id(node),
+            object_id(name)
+        ),
+        config.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_sensor_config_maps_units_and_classes() {
+        let (topic, payload) = sensor_config(
+            "homeassistant",
+            "jotunheim",
+            "node 1",
+            "roomA/temperature",
+            Some("temperature"),
+            Some("celsius"),
+        );
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/node_1/roomA_temperature/config"
+        );
+        let config: JsonValue = serde_json::from_str(&payload).unwrap();
+        assert_eq!(config["unique_id"], "jotunheim_node_1_roomA_temperature");
+        assert_eq!(config["state_topic"], "jotunheim/roomA/temperature");
+        assert_eq!(config["unit_of_measurement"], "°C");
+        assert_eq!(config["device_class"], "temperature");
+    }
+
+    #[cfg(feature = "switch-mqtt")]
+    #[test]
+    fn test_switch_config_uses_command_and_state_topics() {
+        let (topic, payload) = switch_config("homeassistant", "jotunheim", "roomA", "relay");
+        assert_eq!(topic, "homeassistant/switch/roomA/relay/config");
+        let config: JsonValue = serde_json::from_str(&payload).unwrap();
+        assert_eq!(config["command_topic"], "jotunheim/roomA/switch/relay/set");
+        assert_eq!(config["state_topic"], "jotunheim/roomA/switch/relay/state");
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{config::file::PushgatewayConfig, config::Config, msg::EncodeData, CollectorAddr};
+use anyhow::{anyhow, bail};
+use async_std::future::timeout;
+use core::time::Duration;
+use log::{error, info};
+use url::Url;
+use xactor::*;
+
+#[message]
+#[derive(Clone, Debug)]
+struct Push;
+
+/// The push URL for a grouping key, values that can't be part of a path are
+/// base64 encoded as the Pushgateway expects them.
+pub fn grouping_url(base: &str, key: &[(&str, &str)]) -> anyhow::Result<Url> {
+    let mut url = Url::parse(base)?;
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| anyhow!("'{}' can't have a path", base))?;
+        segments.pop_if_empty().push("metrics");
+        for (name, value) in key {
+            if value.is_empty() {
+                segments.push(&format!("{}@base64", name)).push("=");
+            } else if value.contains('/') {
+                segments
+                    .push(&format!("{}@base64", name))
+                    .push(&base64::encode_config(value, base64::URL_SAFE));
+            } else {
+                segments.push(name).push(value);
+            }
+        }
+    }
+    Ok(url)
+}
+
+/// Periodically replaces the metrics of its group on a Pushgateway with the
+/// collector's current `/metrics` output.
+pub(crate) struct PushgatewaySink {
+    url: Url,
+    collector: CollectorAddr,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl PushgatewaySink {
+    pub fn new(
+        config: &PushgatewayConfig,
+        instance: &str,
+        collector: CollectorAddr,
+        interval: Duration,
+    ) -> anyhow::Result<Self> {
+        let mut key = vec![("job", config.job.as_str()), ("instance", instance)];
+        key.extend(
+            config
+                .grouping
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str())),
+        );
+        Ok(PushgatewaySink {
+            url: grouping_url(&config.url, &key)?,
+            collector,
+            interval,
+            timeout: config
+                .timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(interval),
+        })
+    }
+
+    async fn push(&self) -> anyhow::Result<()> {
+        let body = self.collector.call(EncodeData).await??;
+        let request = surf::put(self.url.as_str())
+            .body(body)
+            .header("Content-Type", "text/plain; version=0.0.4");
+        let response = timeout(self.timeout, request)
+            .await
+            .map_err(|_| anyhow!("timed out after {:?}", self.timeout))?
+            .map_err(|e| e.into_inner())?;
+        if !response.status().is_success() {
+            bail!("Pushgateway returned {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for PushgatewaySink {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.send_interval(Push, self.interval);
+        info!(
+            "Pushing metrics to '{}' every {:?}",
+            self.url, self.interval
+        );
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<Push> for PushgatewaySink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Push) {
+        if let Err(e) = self.push().await {
+            error!("Couldn't push to '{}': {}", self.url, e);
+        }
+    }
+}
+
+pub async fn setup(
+    config: &Config,
+    collector: CollectorAddr,
+) -> Result<Option<Addr<PushgatewaySink>>> {
+    match &config.file.pushgateway {
+        Some(pushgateway) => {
+            let interval = pushgateway
+                .interval_ms
+                .map(Duration::from_millis)
+                .unwrap_or_else(|| config.resolution());
+            let instance = pushgateway
+                .instance
+                .clone()
+                .unwrap_or_else(|| config.metrics_name.clone());
+            let sink = PushgatewaySink::new(pushgateway, &instance, collector, interval)?;
+            Ok(Some(sink.start().await?))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_grouping_url_encodes_values() {
+        let url = grouping_url(
+            "http://localhost:9091/",
+            &[
+                ("job", "jotunheim"),
+                ("instance", ""),
+                ("path", "/dev/i2c-1"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://localhost:9091/metrics/job/jotunheim/instance@base64/=/path@base64/L2Rldi9pMmMtMQ=="
+        );
+    }
+}
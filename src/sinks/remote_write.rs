@@ -0,0 +1,397 @@
+use crate::{config::file::RemoteWriteConfig, config::Config, msg::Gather, CollectorAddr};
+use anyhow::{anyhow, bail};
+use async_std::future::timeout;
+use core::time::Duration;
+use log::{debug, error, info, warn};
+use prometheus::proto::{MetricFamily, MetricType};
+use prost::Message as _;
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use xactor::*;
+
+/// The subset of the remote-write protobuf schema (`prometheus.WriteRequest`) we send.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// Milliseconds since the epoch
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+#[message]
+#[derive(Clone, Debug)]
+struct Push;
+
+fn series(name: String, mut labels: Vec<Label>, value: f64, timestamp: i64) -> TimeSeries {
+    labels.push(Label {
+        name: "__name__".into(),
+        value: name,
+    });
+    // remote-write requires labels sorted by name
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    TimeSeries {
+        labels,
+        samples: vec![Sample { value, timestamp }],
+    }
+}
+
+/// Flattens gathered metric families into one time series per sample, the way
+/// the text format would expose them (histograms as `_bucket`, `_sum` and `_count`).
+pub fn to_timeseries(families: &[MetricFamily], now_ms: i64) -> Vec<TimeSeries> {
+    let mut result = vec![];
+    for family in families {
+        let name = family.get_name();
+        for m in family.get_metric() {
+            let timestamp = match m.get_timestamp_ms() {
+                0 => now_ms,
+                ts => ts,
+            };
+            let labels: Vec<Label> = m
+                .get_label()
+                .iter()
+                .map(|l| Label {
+                    name: l.get_name().to_string(),
+                    value: l.get_value().to_string(),
+                })
+                .collect();
+            let with = |extra: &str, value: String| {
+                let mut labels = labels.clone();
+                labels.push(Label {
+                    name: extra.to_string(),
+                    value,
+                });
+                labels
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => result.push(series(
+                    name.into(),
+                    labels.clone(),
+                    m.get_counter().get_value(),
+                    timestamp,
+                )),
+                MetricType::GAUGE => result.push(series(
+                    name.into(),
+                    labels.clone(),
+                    m.get_gauge().get_value(),
+                    timestamp,
+                )),
+                MetricType::UNTYPED => result.push(series(
+                    name.into(),
+                    labels.clone(),
+                    m.get_untyped().get_value(),
+                    timestamp,
+                )),
+                MetricType::HISTOGRAM => {
+                    let h = m.get_histogram();
+                    for b in h.get_bucket() {
+                        result.push(series(
+                            format!("{}_bucket", name),
+                            with("le", b.get_upper_bound().to_string()),
+                            b.get_cumulative_count() as f64,
+                            timestamp,
+                        ));
+                    }
+                    result.push(series(
+                        format!("{}_bucket", name),
+                        with("le", "+Inf".into()),
+                        h.get_sample_count() as f64,
+                        timestamp,
+                    ));
+                    result.push(series(
+                        format!("{}_sum", name),
+                        labels.clone(),
+                        h.get_sample_sum(),
+                        timestamp,
+                    ));
+                    result.push(series(
+                        format!("{}_count", name),
+                        labels.clone(),
+                        h.get_sample_count() as f64,
+                        timestamp,
+                    ));
+                }
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        result.push(series(
+                            name.into(),
+                            with("quantile", q.get_quantile().to_string()),
+                            q.get_value(),
+                            timestamp,
+                        ));
+                    }
+                    result.push(series(
+                        format!("{}_sum", name),
+                        labels.clone(),
+                        s.get_sample_sum(),
+                        timestamp,
+                    ));
+                    result.push(series(
+                        format!("{}_count", name),
+                        labels.clone(),
+                        s.get_sample_count() as f64,
+                        timestamp,
+                    ));
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Snappy-compressed protobuf, as the remote-write protocol expects it.
+pub fn encode(request: &WriteRequest) -> anyhow::Result<Vec<u8>> {
+    snap::raw::Encoder::new()
+        .compress_vec(&request.encode_to_vec())
+        .map_err(From::from)
+}
+
+enum SendError {
+    /// Worth trying again later
+    Retry(anyhow::Error),
+    /// The endpoint rejected the data, it won't take it later either
+    Rejected(anyhow::Error),
+}
+
+/// Periodically gathers the collector's registry and pushes it to a
+/// remote-write endpoint, keeping unsent pushes while it's unreachable.
+pub(crate) struct RemoteWriter {
+    config: RemoteWriteConfig,
+    collector: CollectorAddr,
+    interval: Duration,
+    timeout: Duration,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl RemoteWriter {
+    pub fn new(config: RemoteWriteConfig, collector: CollectorAddr, interval: Duration) -> Self {
+        RemoteWriter {
+            timeout: config
+                .timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(interval),
+            config,
+            collector,
+            interval,
+            pending: VecDeque::new(),
+        }
+    }
+
+    async fn send(&self, body: &[u8]) -> std::result::Result<(), SendError> {
+        // the body sets a content type of its own, so it goes first
+        let mut request = surf::post(&self.config.url)
+            .body(body.to_vec())
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+        if let Some(username) = &self.config.username {
+            let credentials = format!(
+                "{}:{}",
+                username,
+                self.config.password.as_deref().unwrap_or_default()
+            );
+            request = request.header(
+                "Authorization",
+                format!("Basic {}", base64::encode(credentials)),
+            );
+        }
+        for (name, value) in &self.config.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+
+        let response = timeout(self.timeout, request)
+            .await
+            .map_err(|_| SendError::Retry(anyhow!("timed out after {:?}", self.timeout)))?
+            .map_err(|e| SendError::Retry(e.into_inner()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status == surf::StatusCode::TooManyRequests {
+            Err(SendError::Retry(anyhow!("endpoint returned {}", status)))
+        } else {
+            Err(SendError::Rejected(anyhow!("endpoint returned {}", status)))
+        }
+    }
+
+    async fn gather(&self) -> anyhow::Result<Vec<u8>> {
+        let families = self.collector.call(Gather).await?;
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        let request = WriteRequest {
+            timeseries: to_timeseries(&families, now_ms),
+        };
+        if request.timeseries.is_empty() {
+            bail!("nothing to push");
+        }
+        encode(&request)
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for RemoteWriter {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.send_interval(Push, self.interval);
+        info!(
+            "Pushing metrics to '{}' every {:?}",
+            self.config.url, self.interval
+        );
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<Push> for RemoteWriter {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Push) {
+        match self.gather().await {
+            Ok(body) => self.pending.push_back(body),
+            Err(e) => debug!("Skipping push: {}", e),
+        }
+        while self.pending.len() > self.config.max_pending.max(1) {
+            warn!("Remote-write buffer full, dropping the oldest push");
+            self.pending.pop_front();
+        }
+
+        // oldest first, so the endpoint receives samples in order
+        while let Some(body) = self.pending.front() {
+            match self.send(body).await {
+                Ok(()) => {
+                    self.pending.pop_front();
+                }
+                Err(SendError::Rejected(e)) => {
+                    error!("Dropping push to '{}': {}", self.config.url, e);
+                    self.pending.pop_front();
+                }
+                Err(SendError::Retry(e)) => {
+                    warn!(
+                        "Couldn't push to '{}', {} pushes pending: {}",
+                        self.config.url,
+                        self.pending.len(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub async fn setup(
+    config: &Config,
+    collector: CollectorAddr,
+) -> Result<Option<Addr<RemoteWriter>>> {
+    match &config.file.remote_write {
+        Some(remote_write) => {
+            let interval = remote_write
+                .interval_ms
+                .map(Duration::from_millis)
+                .unwrap_or_else(|| config.resolution());
+            let writer = RemoteWriter::new(remote_write.clone(), collector, interval);
+            Ok(Some(writer.start().await?))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use prometheus::{GaugeVec, HistogramOpts, HistogramVec, Opts, Registry};
+    use prost::Message as _;
+
+    #[test]
+    fn test_to_timeseries_flattens_families() {
+        let registry = Registry::new();
+        let gauge = GaugeVec::new(Opts::new("roomA", "help"), &["kind"]).unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("duration_seconds", "help").buckets(vec![1.0]),
+            &["path"],
+        )
+        .unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        gauge.with_label_values(&["temperature"]).set(21.5);
+        histogram.with_label_values(&["/bin/true"]).observe(0.5);
+
+        let series = to_timeseries(&registry.gather(), 1_000);
+        let flat: Vec<(Vec<(&str, &str)>, f64)> = series
+            .iter()
+            .map(|s| {
+                assert_eq!(s.samples[0].timestamp, 1_000);
+                let labels = s
+                    .labels
+                    .iter()
+                    .map(|l| (l.name.as_str(), l.value.as_str()))
+                    .collect();
+                (labels, s.samples[0].value)
+            })
+            .collect();
+        assert_eq!(
+            flat,
+            vec![
+                (
+                    vec![
+                        ("__name__", "duration_seconds_bucket"),
+                        ("le", "1"),
+                        ("path", "/bin/true")
+                    ],
+                    1.0
+                ),
+                (
+                    vec![
+                        ("__name__", "duration_seconds_bucket"),
+                        ("le", "+Inf"),
+                        ("path", "/bin/true")
+                    ],
+                    1.0
+                ),
+                (
+                    vec![("__name__", "duration_seconds_sum"), ("path", "/bin/true")],
+                    0.5
+                ),
+                (
+                    vec![
+                        ("__name__", "duration_seconds_count"),
+                        ("path", "/bin/true")
+                    ],
+                    1.0
+                ),
+                (vec![("__name__", "roomA"), ("kind", "temperature")], 21.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_roundtrips() {
+        let request = WriteRequest {
+            timeseries: vec![series("up".into(), vec![], 1.0, 42)],
+        };
+        let body = encode(&request).unwrap();
+        let raw = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        assert_eq!(WriteRequest::decode(raw.as_slice()).unwrap(), request);
+    }
+}
@@ -1,5 +1,7 @@
-use crate::msg::SetupMetrics;
 pub mod gpio;
+#[cfg(feature = "switch-mqtt")]
+pub mod mqtt;
+pub mod state;
 
 pub mod http_handlers {
 
@@ -15,12 +17,17 @@ pub mod http_handlers {
     use crate::{
         config::Config,
         msg::{Switch, SwitchState},
-        switches::gpio::GpioSwitch,
+        switches::{gpio::GpioSwitch, state::SwitchStateFile},
     };
 
+    #[cfg(feature = "switch-mqtt")]
+    use crate::switches::mqtt::MqttSwitches;
+
     #[derive(Clone)]
     pub struct SwitchHttpState {
         gpio: HashMap<String, SwitchAddr>,
+        // the broker only keeps a weak reference, this keeps it running
+        _state_file: Option<Addr<SwitchStateFile>>,
     }
 
     pub async fn switch(req: Request<SwitchHttpState>) -> tide::Result {
@@ -58,15 +65,49 @@ pub mod http_handlers {
         Ok(resp)
     }
 
+    #[cfg(feature = "switch-mqtt")]
+    async fn start_mqtt(config: &Config, switches: HashMap<String, SwitchAddr>) -> Result<()> {
+        if switches.is_empty() {
+            return Ok(());
+        }
+        let settings = config.file.mqtt.clone().unwrap_or_default();
+        let address = match &settings.connection {
+            Some(c) => c.parse()?,
+            None => match config.mqtt_address() {
+                Ok(address) => address,
+                Err(_) => {
+                    info!("No MQTT connection configured, switches are only available via HTTP");
+                    return Ok(());
+                }
+            },
+        };
+        // its eventloop holds an address, so it keeps running
+        MqttSwitches::new(address, config.metrics_name.clone(), settings, switches)?
+            .start()
+            .await?;
+        Ok(())
+    }
+
     pub async fn init_and_setup(config: &Config) -> Result<Server<SwitchHttpState>> {
-        let gpios = config.parsed_gpios().await;
+        let gpios = config.gpio_switches().await;
         let mut switches = HashMap::new();
+        let mut state_file_addr = None;
         if !gpios.is_empty() {
             info!("GPIO module active");
+            let state_file = match &config.file.switch_state_file {
+                Some(path) => Some(SwitchStateFile::load(path)?),
+                None => None,
+            };
             let switches_actors: HashMap<String, GpioSwitch> = gpios
                 .into_iter()
-                .map(|(n, p)| (n.clone(), GpioSwitch::new(p, n)))
+                .map(|g| {
+                    let restored = state_file.as_ref().and_then(|s| s.get(&g.name));
+                    (g.name.clone(), GpioSwitch::new(g, restored))
+                })
                 .collect();
+            if let Some(state_file) = state_file {
+                state_file_addr = Some(state_file.start().await?);
+            }
             info!(
                 "GPIOs activated: {:?}",
                 switches_actors.keys().collect::<Vec<&String>>()
@@ -77,7 +118,13 @@ pub mod http_handlers {
             }
         }
 
-        let mut app = tide::with_state(SwitchHttpState { gpio: switches });
+        #[cfg(feature = "switch-mqtt")]
+        start_mqtt(config, switches.clone()).await?;
+
+        let mut app = tide::with_state(SwitchHttpState {
+            gpio: switches,
+            _state_file: state_file_addr,
+        });
         app.at("/:id/").get(switch_status);
         app.at("/:id/:value").get(switch);
         Ok(app)
@@ -1,5 +1,7 @@
-use crate::msg::{Switch, SwitchState, Value};
-use crate::switches::SetupMetrics;
+use crate::config::file::{GpioConfig, InitialState};
+use crate::msg::{
+    MetricSpec, SetupMetrics, SetupSwitch, Switch, SwitchChanged, SwitchState, Value,
+};
 use log::info;
 use rust_gpiozero::*;
 use uuid::Uuid;
@@ -11,50 +13,109 @@ use crate::msg::{ReadNow, SensorReading};
 
 pub(crate) struct GpioSwitch {
     dev: DigitalOutputDevice,
+    /// Logical state, on is low for active-low switches
     state: bool,
+    active_high: bool,
+    initial: Option<InitialState>,
+    read_back: bool,
+    /// Last commanded state from the state file
+    restored: Option<bool>,
     collector_id: Uuid,
     name: String,
     pin_no: u32,
 }
 
 impl GpioSwitch {
-    pub fn new<I: Into<String>>(pin_no: u32, name: I) -> Self {
-        let dev = DigitalOutputDevice::new(pin_no as u8);
+    pub fn new(config: GpioConfig, restored: Option<bool>) -> Self {
+        let mut dev = DigitalOutputDevice::new(config.pin as u8);
+        dev.set_active_high(config.active_high);
         let collector_id = Uuid::new_v4();
         GpioSwitch {
             dev,
-            state: false, // major assumption :)
+            state: false,
+            active_high: config.active_high,
+            initial: config.initial,
+            read_back: config.read_back,
+            restored,
             collector_id,
-            name: name.into(),
-            pin_no,
+            name: config.name,
+            pin_no: config.pin,
         }
     }
+
+    fn set(&mut self, msg: Switch) {
+        info!("Setting GPIO '{}' to {:?}", self.name, msg);
+        match msg {
+            Switch::On => self.dev.on(),
+            Switch::Off => self.dev.off(),
+        }
+        self.state = msg == Switch::On;
+    }
+
+    async fn publish_state(&self) -> Result<()> {
+        Broker::from_registry().await?.publish(SensorReading {
+            id: self.collector_id,
+            reading: Value::Simple(if self.state { 1.0 } else { 0.0 }),
+            labels: vec![self.name.clone()],
+            timestamp: None,
+        })
+    }
 }
 
 #[async_trait::async_trait]
 impl Actor for GpioSwitch {
     async fn started(&mut self, _ctx: &mut Context<Self>) -> anyhow::Result<()> {
+        Broker::from_registry().await?.publish(SetupSwitch {
+            name: self.name.clone(),
+        })?;
+
         let mut addr = Broker::from_registry().await?;
         addr.publish(SetupMetrics::Gauge(
-            self.collector_id,
-            format!("switch:{}", self.name),
-            vec![String::from("name")],
+            MetricSpec::new(
+                self.collector_id,
+                self.name.clone(),
+                vec![String::from("name")],
+            )
+            .subsystem("switch")
+            .help(format!(
+                "State of the switch on GPIO {}{}, 1 is on",
+                self.pin_no,
+                if self.active_high {
+                    ""
+                } else {
+                    " (active low)"
+                }
+            )),
         ))?;
 
-        Ok(())
+        let initial = match self.initial {
+            Some(InitialState::On) => Some(Switch::On),
+            Some(InitialState::Off) => Some(Switch::Off),
+            Some(InitialState::Restore) => {
+                self.restored
+                    .map(|on| if on { Switch::On } else { Switch::Off })
+            }
+            None => None,
+        };
+        match initial {
+            Some(switch) => self.set(switch),
+            None if self.read_back => self.state = self.dev.is_active(),
+            // without reading the pin, off is all we can assume
+            None => {}
+        }
+        info!(
+            "GPIO '{}' starts {}",
+            self.name,
+            if self.state { "on" } else { "off" }
+        );
+        self.publish_state().await
     }
 }
 
 #[async_trait::async_trait]
 impl Handler<ReadNow> for GpioSwitch {
     async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: ReadNow) {
-        let mut addr = Broker::from_registry().await.unwrap();
-
-        let _ = addr.publish(SensorReading {
-            id: self.collector_id,
-            reading: Value::Simple(if self.state { 1.0 } else { 0.0 }),
-            labels: vec![self.name.clone()],
-        });
+        let _ = self.publish_state().await;
     }
 }
 
@@ -68,11 +129,11 @@ impl Handler<SwitchState> for GpioSwitch {
 #[async_trait::async_trait]
 impl Handler<Switch> for GpioSwitch {
     async fn handle(&mut self, _ctx: &mut Context<Self>, msg: Switch) -> Result<()> {
-        info!("Setting GPIO '{}' to {:?}", self.name, msg);
-        let _value = match msg {
-            Switch::On => self.dev.on(),
-            Switch::Off => self.dev.off(),
-        };
-        Ok(())
+        self.set(msg);
+        Broker::from_registry().await?.publish(SwitchChanged {
+            name: self.name.clone(),
+            on: self.state,
+        })?;
+        self.publish_state().await
     }
 }
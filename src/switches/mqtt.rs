@@ -0,0 +1,216 @@
+use super::http_handlers::SwitchAddr;
+use crate::{
+    config::file::MqttSinkConfig,
+    msg::{Switch, SwitchState},
+    utils::mqtt::{self, qos, switch_topic, topic_level},
+};
+use anyhow::Result;
+use async_std::task::{self, JoinHandle};
+use core::time::Duration;
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use std::collections::HashMap;
+use url::Url;
+use uuid::Uuid;
+use xactor::*;
+
+/// Switches `name` as commanded on its MQTT topic.
+#[message]
+#[derive(Clone, Debug)]
+struct Command {
+    name: String,
+    switch: Switch,
+}
+
+/// Publishes the state of every switch, e.g. after (re)connecting.
+#[message]
+#[derive(Clone, Debug)]
+struct PublishStates;
+
+/// Maps a message on `<prefix>/<node>/switch/<name>/set` to the switch name
+/// and command, accepts `ON`/`OFF` and `1`/`0`.
+pub fn parse_command(
+    prefix: &str,
+    node: &str,
+    topic: &str,
+    payload: &[u8],
+) -> Option<(String, Switch)> {
+    let name = topic
+        .strip_prefix(&format!("{}/{}/switch/", prefix, topic_level(node)))?
+        .strip_suffix("/set")?;
+    let switch = match String::from_utf8_lossy(payload).trim() {
+        p if p.eq_ignore_ascii_case("on") || p == "1" => Switch::On,
+        p if p.eq_ignore_ascii_case("off") || p == "0" => Switch::Off,
+        _ => return None,
+    };
+    Some((name.to_string(), switch))
+}
+
+/// Controls GPIO switches via `<prefix>/<node>/switch/<name>/set` and
+/// publishes their state retained on `.../state`.
+pub(crate) struct MqttSwitches {
+    address: Url,
+    node: String,
+    config: MqttSinkConfig,
+    qos: QoS,
+    switches: HashMap<String, SwitchAddr>,
+    client: Option<AsyncClient>,
+    eventloop_task: Option<JoinHandle<()>>,
+}
+
+impl MqttSwitches {
+    pub fn new(
+        address: Url,
+        node: String,
+        mut config: MqttSinkConfig,
+        switches: HashMap<String, SwitchAddr>,
+    ) -> Result<Self> {
+        config.prefix = config.prefix.trim_end_matches('/').to_string();
+        Ok(MqttSwitches {
+            address,
+            node,
+            qos: qos(config.qos)?,
+            config,
+            switches,
+            client: None,
+            eventloop_task: None,
+        })
+    }
+
+    async fn publish_state(&self, name: &str) -> Result<()> {
+        if let (Some(client), Some(switch)) = (&self.client, self.switches.get(name)) {
+            let state = if switch.call(SwitchState).await? {
+                "ON"
+            } else {
+                "OFF"
+            };
+            let topic = format!(
+                "{}/state",
+                switch_topic(&self.config.prefix, &self.node, name)
+            );
+            client.publish(topic, self.qos, true, state).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for MqttSwitches {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> anyhow::Result<()> {
+        let options = mqtt::options(
+            format!("jotunheim-switches-{}", Uuid::new_v4().as_simple()),
+            &self.address,
+            self.config.username.as_deref(),
+            self.config.password.as_deref(),
+        )?;
+        let (client, mut eventloop) = AsyncClient::new(options, 1000);
+        let subscriptions = client.clone();
+        let filter = format!(
+            "{}/{}/switch/+/set",
+            self.config.prefix,
+            topic_level(&self.node)
+        );
+        let (prefix, node, qos) = (self.config.prefix.clone(), self.node.clone(), self.qos);
+        let addr = ctx.address();
+        self.eventloop_task = Some(task::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    // subscriptions don't survive a reconnect
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        if let Err(e) = subscriptions.try_subscribe(&filter, qos) {
+                            error!("Couldn't subscribe to '{}': {}", filter, e);
+                        }
+                        let _ = addr.send(PublishStates);
+                    }
+                    Ok(Event::Incoming(Packet::Publish(p))) => {
+                        match parse_command(&prefix, &node, &p.topic, &p.payload) {
+                            Some((name, switch)) => {
+                                let _ = addr.send(Command { name, switch });
+                            }
+                            None => warn!(
+                                "Ignoring '{}' on '{}'",
+                                String::from_utf8_lossy(&p.payload),
+                                p.topic
+                            ),
+                        }
+                    }
+                    Ok(_) => {}
+                    // polling again reconnects
+                    Err(e) => {
+                        error!("MQTT connection failed: {:?}", e);
+                        task::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        }));
+        self.client = Some(client);
+        info!(
+            "Listening for switch commands on '{}/{}/switch/<name>/set'",
+            self.config.prefix, self.node
+        );
+        Ok(())
+    }
+
+    async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        if let Some(client) = self.client.take() {
+            let _ = client.disconnect().await;
+        }
+        if let Some(eventloop_task) = self.eventloop_task.take() {
+            eventloop_task.cancel().await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<Command> for MqttSwitches {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: Command) {
+        let switch = match self.switches.get(&msg.name) {
+            Some(switch) => switch,
+            None => {
+                warn!("No switch called '{}'", msg.name);
+                return;
+            }
+        };
+        info!("MQTT: setting '{}' to {:?}", msg.name, msg.switch);
+        match switch.call(msg.switch).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) | Err(e) => error!("Couldn't switch '{}': {}", msg.name, e),
+        }
+        if let Err(e) = self.publish_state(&msg.name).await {
+            error!("Couldn't publish the state of '{}': {}", msg.name, e);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<PublishStates> for MqttSwitches {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: PublishStates) {
+        for name in self.switches.keys() {
+            if let Err(e) = self.publish_state(name).await {
+                error!("Couldn't publish the state of '{}': {}", name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_parse_command_accepts_on_off_and_digits() {
+        let parse = |topic, payload: &[u8]| parse_command("jotunheim", "roomA", topic, payload);
+        assert_eq!(
+            parse("jotunheim/roomA/switch/relay/set", b"ON"),
+            Some(("relay".into(), Switch::On))
+        );
+        assert_eq!(
+            parse("jotunheim/roomA/switch/relay/set", b"0\n"),
+            Some(("relay".into(), Switch::Off))
+        );
+        assert_eq!(parse("jotunheim/roomB/switch/relay/set", b"1"), None);
+        assert_eq!(parse("jotunheim/roomA/switch/relay/state", b"1"), None);
+        assert_eq!(parse("jotunheim/roomA/switch/relay/set", b"TOGGLE"), None);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::msg::SwitchChanged;
+use anyhow::Result;
+use log::{error, info};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use xactor::*;
+
+/// Keeps the last commanded state of every switch in a TOML file like
+/// `relay = true`, so they can be restored after a restart.
+pub(crate) struct SwitchStateFile {
+    path: PathBuf,
+    states: BTreeMap<String, bool>,
+}
+
+impl SwitchStateFile {
+    /// Reads the states from `path`, a missing file has no states yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let states = match fs::read_to_string(&path) {
+            Ok(raw) => toml::from_str(&raw)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(SwitchStateFile { path, states })
+    }
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        self.states.get(name).copied()
+    }
+
+    /// Writes a temporary file first, so a crash never leaves a truncated one.
+    fn save(&self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, toml::to_string(&self.states)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for SwitchStateFile {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.subscribe::<SwitchChanged>().await?;
+        info!("Keeping switch states in '{}'", self.path.display());
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<SwitchChanged> for SwitchStateFile {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SwitchChanged) {
+        if self.states.insert(msg.name, msg.on) != Some(msg.on) {
+            if let Err(e) = self.save() {
+                error!(
+                    "Couldn't save switch states to '{}': {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_SwitchStateFile_roundtrips() {
+        let path = std::env::temp_dir().join(format!("jotunheim-{}.toml", Uuid::new_v4()));
+        let mut file = SwitchStateFile::load(&path).unwrap();
+        assert_eq!(file.get("relay"), None);
+
+        file.states.insert("relay".into(), true);
+        file.states.insert("fan".into(), false);
+        file.save().unwrap();
+
+        let restored = SwitchStateFile::load(&path).unwrap();
+        assert_eq!(restored.get("relay"), Some(true));
+        assert_eq!(restored.get("fan"), Some(false));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_SwitchStateFile_saves_changes() {
+        let path = std::env::temp_dir().join(format!("jotunheim-{}.toml", Uuid::new_v4()));
+        let addr = SwitchStateFile::load(&path).unwrap().start().await.unwrap();
+        addr.call(SwitchChanged {
+            name: "relay".into(),
+            on: true,
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "relay = true\n");
+
+        addr.call(SwitchChanged {
+            name: "fan".into(),
+            on: false,
+        })
+        .await
+        .unwrap();
+        let restored = SwitchStateFile::load(&path).unwrap();
+        assert_eq!(restored.get("relay"), Some(true));
+        assert_eq!(restored.get("fan"), Some(false));
+        drop(addr);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,3 +1,8 @@
+use crate::config::file::Labels;
+
+#[cfg(feature = "rumqttc")]
+pub mod mqtt;
+
 pub fn e_<E: Into<anyhow::Error>>(err: E) -> anyhow::Error {
     err.into()
 }
@@ -10,6 +15,20 @@ pub fn max(v: &[f64]) -> f64 {
     v.iter().fold(0_f64, |p, c| p.max(*c))
 }
 
+/// Label names for `SetupMetrics`, followed by the names of the static labels.
+pub fn label_names(names: &[&str], extra: &Labels) -> Vec<String> {
+    names
+        .iter()
+        .map(|n| n.to_string())
+        .chain(extra.keys().cloned())
+        .collect()
+}
+
+/// Label values for a `SensorReading`, followed by the values of the static labels.
+pub fn label_values(values: Vec<String>, extra: &Labels) -> Vec<String> {
+    values.into_iter().chain(extra.values().cloned()).collect()
+}
+
 #[macro_export]
 macro_rules! extract_from {
     ( $coll: expr, $cls: path ) => {{
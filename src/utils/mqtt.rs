@@ -0,0 +1,50 @@
+use anyhow::{anyhow, bail, Result};
+use core::time::Duration;
+use rumqttc::{MqttOptions, QoS};
+use url::Url;
+
+/// Connection options for the broker at `address`, `client_id` has to be
+/// unique per broker.
+pub fn options(
+    client_id: String,
+    address: &Url,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<MqttOptions> {
+    let mut options = MqttOptions::new(
+        client_id,
+        address
+            .host_str()
+            .ok_or_else(|| anyhow!("'{}' has no host", address))?,
+        address.port().unwrap_or(1883),
+    );
+    options.set_keep_alive(Duration::from_secs(5));
+    if let Some(username) = username {
+        options.set_credentials(username, password.unwrap_or_default());
+    }
+    Ok(options)
+}
+
+pub fn qos(level: u8) -> Result<QoS> {
+    match level {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        other => bail!("Invalid MQTT QoS {}, expected 0, 1 or 2", other),
+    }
+}
+
+/// Replaces characters that would split or wildcard a topic level.
+pub fn topic_level(s: &str) -> String {
+    s.replace(&['/', '+', '#'][..], "_")
+}
+
+/// `<prefix>/<node>/switch/<name>`, commands go to `/set` and the state to `/state`.
+pub fn switch_topic(prefix: &str, node: &str, name: &str) -> String {
+    format!(
+        "{}/{}/switch/{}",
+        prefix,
+        topic_level(node),
+        topic_level(name)
+    )
+}